@@ -0,0 +1,7 @@
+-- one Argon2id hash (PHC string format) per user, gone together with the user
+CREATE TABLE IF NOT EXISTS credentials
+(
+    user_id UUID PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    password_hash TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,34 @@
+-- roles with named permissions replace the free-text users.role column,
+-- the permissions and built-in roles match the Permission and Role enums in src/roles.rs
+CREATE TABLE IF NOT EXISTS roles
+(
+    name TEXT PRIMARY KEY CHECK (name ~ '^[a-z][a-z0-9_]*$'),
+
+    description TEXT NOT NULL DEFAULT '',
+    permissions TEXT[] NOT NULL DEFAULT '{}' CHECK (permissions <@ '{users:read,users:list,users:edit,users:ban,users:delete,roles:manage}'),
+    built_in BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+INSERT INTO roles (name, description, permissions, built_in) VALUES
+    ('user', 'Manages their own account.', '{}', TRUE),
+    ('moderator', 'Reads and lists every user, bans and unbans them.', '{users:read,users:list,users:ban}', TRUE),
+    ('admin', 'Does everything.', '{users:read,users:list,users:edit,users:ban,users:delete,roles:manage}', TRUE);
+
+-- a user can have several roles
+CREATE TABLE IF NOT EXISTS user_roles
+(
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    role TEXT NOT NULL REFERENCES roles (name) ON UPDATE CASCADE,
+    granted_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    PRIMARY KEY (user_id, role)
+);
+
+CREATE INDEX IF NOT EXISTS user_roles_role_idx ON user_roles (role);
+
+-- existing role strings are kept when they name a built-in role, anything else becomes a plain user
+INSERT INTO user_roles (user_id, role)
+SELECT users.id, COALESCE(roles.name, 'user')
+FROM users LEFT JOIN roles ON roles.name = lower(trim(users.role));
+
+ALTER TABLE users DROP COLUMN role;
@@ -0,0 +1,28 @@
+-- bans replace the banned flag, a user is banned while they have a ban that is neither lifted nor expired
+CREATE TABLE IF NOT EXISTS bans
+(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+
+    -- NULL when the ban was made with an API key or the moderator has been deleted
+    moderator_id UUID REFERENCES users (id) ON DELETE SET NULL,
+
+    reason TEXT NOT NULL CHECK (length(trim(reason)) > 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    -- NULL for a permanent ban
+    expires_at TIMESTAMPTZ CHECK (expires_at > created_at),
+
+    -- an expired ban is marked lifted when it expired, with no lifted_by
+    lifted_at TIMESTAMPTZ,
+    lifted_by UUID REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS bans_user_id_idx ON bans (user_id, created_at);
+CREATE INDEX IF NOT EXISTS bans_expires_at_idx ON bans (expires_at) WHERE lifted_at IS NULL;
+
+-- users banned with the old flag keep a permanent ban
+INSERT INTO bans (user_id, reason)
+SELECT id, 'Banned before bans had reasons.' FROM users WHERE banned;
+
+ALTER TABLE users DROP COLUMN banned;
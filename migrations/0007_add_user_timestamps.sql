@@ -0,0 +1,39 @@
+-- updated_at follows changes to the user's own columns, last_login_at is set by the service on login
+ALTER TABLE users ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_login_at TIMESTAMPTZ;
+
+-- existing users have not changed since they were created as far as we know
+UPDATE users SET updated_at = created_at WHERE updated_at IS NULL;
+
+ALTER TABLE users ALTER COLUMN updated_at SET DEFAULT now();
+ALTER TABLE users ALTER COLUMN updated_at SET NOT NULL;
+
+-- every login starts a new family of refresh tokens, its first token tells when that was
+UPDATE users SET last_login_at =
+(
+    SELECT max(started_at) FROM (SELECT min(created_at) AS started_at FROM refresh_tokens WHERE user_id = users.id GROUP BY family_id) AS logins
+)
+WHERE last_login_at IS NULL;
+
+-- created_at and updated_at cannot be set by hand, a login (or writing the same values again) is not an update
+CREATE OR REPLACE FUNCTION users_touch_updated_at() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        NEW.created_at := now();
+        NEW.updated_at := NEW.created_at;
+    ELSE
+        NEW.created_at := OLD.created_at;
+        NEW.updated_at := CASE
+            WHEN to_jsonb(NEW) - 'updated_at' - 'last_login_at' = to_jsonb(OLD) - 'updated_at' - 'last_login_at' THEN OLD.updated_at
+            ELSE now()
+        END;
+    END IF;
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS users_touch_updated_at ON users;
+
+CREATE TRIGGER users_touch_updated_at BEFORE INSERT OR UPDATE ON users
+FOR EACH ROW EXECUTE FUNCTION users_touch_updated_at();
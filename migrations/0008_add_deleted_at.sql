@@ -0,0 +1,22 @@
+-- deleting a user only marks it, the service purges it for good once the grace period is over
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS users_deleted_at_idx ON users (deleted_at) WHERE deleted_at IS NOT NULL;
+
+-- deleting and restoring are not updates either
+CREATE OR REPLACE FUNCTION users_touch_updated_at() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        NEW.created_at := now();
+        NEW.updated_at := NEW.created_at;
+    ELSE
+        NEW.created_at := OLD.created_at;
+        NEW.updated_at := CASE
+            WHEN to_jsonb(NEW) - 'updated_at' - 'last_login_at' - 'deleted_at' = to_jsonb(OLD) - 'updated_at' - 'last_login_at' - 'deleted_at' THEN OLD.updated_at
+            ELSE now()
+        END;
+    END IF;
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
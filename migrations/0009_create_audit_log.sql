@@ -0,0 +1,42 @@
+-- every change to a user, written in the same transaction as the change itself
+CREATE TABLE IF NOT EXISTS audit_log
+(
+    id BIGSERIAL PRIMARY KEY,
+
+    -- actor_id goes with user, actor_key with api_key
+    actor_type TEXT NOT NULL CHECK (actor_type IN ('user', 'api_key', 'anonymous', 'system')),
+    actor_id UUID,
+    actor_key TEXT,
+
+    -- matches the Action enum in src/audit.rs
+    action TEXT NOT NULL CHECK (action IN ('created', 'updated', 'deleted', 'restored', 'purged', 'password_changed', 'role_assigned', 'role_revoked', 'banned', 'unbanned')),
+
+    -- no foreign key, the entries outlive the users they are about
+    user_id UUID NOT NULL,
+    changes JSONB NOT NULL DEFAULT '{}',
+
+    request_id TEXT,
+    source_ip INET,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_user_id_idx ON audit_log (user_id, id);
+CREATE INDEX IF NOT EXISTS audit_log_actor_id_idx ON audit_log (actor_id, id) WHERE actor_id IS NOT NULL;
+
+-- entries can be added but never changed or removed
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS audit_log_append_only ON audit_log;
+
+CREATE TRIGGER audit_log_append_only BEFORE UPDATE OR DELETE OR TRUNCATE ON audit_log
+FOR EACH STATEMENT EXECUTE FUNCTION audit_log_append_only();
+
+-- reading the audit log is a permission of its own, admins get it
+ALTER TABLE roles DROP CONSTRAINT IF EXISTS roles_permissions_check;
+ALTER TABLE roles ADD CONSTRAINT roles_permissions_check CHECK (permissions <@ '{users:read,users:list,users:edit,users:ban,users:delete,roles:manage,audit:read}');
+
+UPDATE roles SET permissions = array_append(permissions, 'audit:read') WHERE name = 'admin' AND NOT 'audit:read' = ANY(permissions);
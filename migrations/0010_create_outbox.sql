@@ -0,0 +1,17 @@
+-- events for other services, written in the same transaction as the change and removed once a sink took them
+CREATE TABLE IF NOT EXISTS outbox
+(
+    id BIGSERIAL PRIMARY KEY,
+
+    -- matches the Kind enum in src/outbox.rs
+    type TEXT NOT NULL CHECK (type IN ('UserCreated', 'UserUpdated', 'UserBanned', 'UserDeleted')),
+
+    -- no foreign key, the event has to be delivered even if the user is purged first
+    user_id UUID NOT NULL,
+    data JSONB NOT NULL,
+    occurred_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    -- failed deliveries, the event stays first in line until it goes through
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT
+);
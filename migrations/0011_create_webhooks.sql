@@ -0,0 +1,46 @@
+-- partner services subscribed to the events of the outbox
+CREATE TABLE IF NOT EXISTS webhooks
+(
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    url TEXT NOT NULL,
+    events TEXT[] NOT NULL CHECK (cardinality(events) > 0 AND events <@ '{created,updated,banned,deleted}'),
+
+    -- kept as it is, signing needs the secret itself
+    secret TEXT NOT NULL,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- every event sent to a webhook, the log stays until the webhook is deleted
+CREATE TABLE IF NOT EXISTS webhook_deliveries
+(
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id UUID NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+
+    -- the id of the outbox event, NULL for a test ping
+    event_id BIGINT,
+    event TEXT NOT NULL CHECK (event IN ('created', 'updated', 'banned', 'deleted', 'ping')),
+    payload JSONB NOT NULL,
+
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'delivered', 'dead')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+
+    -- when a pending delivery is due, pushed back while a replica is sending it
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    -- of the last attempt, the status is NULL when the webhook could not be reached
+    response_status INTEGER,
+    last_error TEXT,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    delivered_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_due_idx ON webhook_deliveries (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook_id_idx ON webhook_deliveries (webhook_id, id);
+
+-- managing webhooks is a permission of its own, admins get it
+ALTER TABLE roles DROP CONSTRAINT IF EXISTS roles_permissions_check;
+ALTER TABLE roles ADD CONSTRAINT roles_permissions_check CHECK (permissions <@ '{users:read,users:list,users:edit,users:ban,users:delete,roles:manage,audit:read,webhooks:manage}');
+
+UPDATE roles SET permissions = array_append(permissions, 'webhooks:manage') WHERE name = 'admin' AND NOT 'webhooks:manage' = ANY(permissions);
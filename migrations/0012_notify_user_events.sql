@@ -0,0 +1,63 @@
+-- every change to a user is announced on the user_events channel once its transaction commits, for the other replicas
+CREATE OR REPLACE FUNCTION outbox_notify() RETURNS TRIGGER AS $$
+DECLARE
+    payload TEXT := json_build_object(
+        'id', NEW.id,
+        'type', NEW.type,
+        'user_id', NEW.user_id,
+        'data', NEW.data,
+        'occurred_at', to_char(NEW.occurred_at AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS.US"Z"')
+    )::text;
+BEGIN
+    -- payloads are limited to 8000 bytes, larger events are only named and read back from the outbox by the listener
+    IF octet_length(payload) >= 8000 THEN
+        payload := json_build_object('id', NEW.id)::text;
+    END IF;
+
+    PERFORM pg_notify('user_events', payload);
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS outbox_notify ON outbox;
+
+CREATE TRIGGER outbox_notify AFTER INSERT ON outbox
+FOR EACH ROW EXECUTE FUNCTION outbox_notify();
+
+-- changes to the users table that wrote no event, made in SQL or by a login; the listener reads the user back
+CREATE OR REPLACE FUNCTION users_notify() RETURNS TRIGGER AS $$
+DECLARE
+    changed UUID := CASE WHEN TG_OP = 'DELETE' THEN OLD.id ELSE NEW.id END;
+BEGIN
+    -- the event written in the same transaction says more, and the outbox trigger announced it already
+    IF EXISTS (SELECT 1 FROM outbox WHERE user_id = changed AND xmin = pg_current_xact_id()::xid) THEN
+        RETURN NULL;
+    END IF;
+
+    -- purging a user who was deleted before is not announced
+    IF TG_OP = 'DELETE' AND OLD.deleted_at IS NOT NULL THEN
+        RETURN NULL;
+    END IF;
+
+    PERFORM pg_notify('user_events', json_build_object(
+        'id', nextval(pg_get_serial_sequence('outbox', 'id')),
+        'type', CASE
+            WHEN TG_OP = 'INSERT' THEN 'UserCreated'
+            WHEN TG_OP = 'DELETE' OR (OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL) THEN 'UserDeleted'
+            ELSE 'UserUpdated'
+        END,
+        'user_id', changed,
+        'occurred_at', to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS.US"Z"')
+    )::text);
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS users_notify ON users;
+
+-- deferred to the commit, by when the service has written its event if there is one
+CREATE CONSTRAINT TRIGGER users_notify AFTER INSERT OR UPDATE OR DELETE ON users
+DEFERRABLE INITIALLY DEFERRED
+FOR EACH ROW EXECUTE FUNCTION users_notify();
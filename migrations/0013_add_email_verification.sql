@@ -0,0 +1,37 @@
+-- whether the user proved that the email is theirs, nobody has so far
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- tokens sent to prove an email, stored as SHA-256 hashes along with the address they were sent to,
+-- so one sent before the email changed cannot verify the new one
+CREATE TABLE IF NOT EXISTS email_verifications
+(
+    token_hash TEXT PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS email_verifications_user_id_idx ON email_verifications (user_id);
+
+-- verifying the email is not a change to the name or email, updated_at stays as it is, as it does on a delete or restore
+CREATE OR REPLACE FUNCTION users_touch_updated_at() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        NEW.created_at := now();
+        NEW.updated_at := NEW.created_at;
+    ELSE
+        NEW.created_at := OLD.created_at;
+        NEW.updated_at := CASE
+            WHEN to_jsonb(NEW) - 'updated_at' - 'last_login_at' - 'deleted_at' - 'email_verified' = to_jsonb(OLD) - 'updated_at' - 'last_login_at' - 'deleted_at' - 'email_verified' THEN OLD.updated_at
+            ELSE now()
+        END;
+    END IF;
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- matches the Action enum in src/audit.rs
+ALTER TABLE audit_log DROP CONSTRAINT IF EXISTS audit_log_action_check;
+ALTER TABLE audit_log ADD CONSTRAINT audit_log_action_check CHECK (action IN ('created', 'updated', 'deleted', 'restored', 'purged', 'password_changed', 'role_assigned', 'role_revoked', 'banned', 'unbanned', 'email_verified'));
@@ -0,0 +1,22 @@
+-- tokens sent to reset a password, stored as SHA-256 hashes along with the address they were sent to,
+-- so one sent before the email changed cannot be used afterwards
+CREATE TABLE IF NOT EXISTS password_resets
+(
+    token_hash TEXT PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS password_resets_user_id_idx ON password_resets (user_id);
+
+-- every reset asked for, whether or not the email belongs to anyone, to limit them per address
+CREATE TABLE IF NOT EXISTS password_reset_requests
+(
+    source_ip INET NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS password_reset_requests_source_ip_idx ON password_reset_requests (source_ip);
+CREATE INDEX IF NOT EXISTS password_reset_requests_created_at_idx ON password_reset_requests (created_at);
@@ -0,0 +1,9 @@
+-- lifting a ban or its expiry is announced as an event of its own, with the ban in it
+ALTER TABLE outbox DROP CONSTRAINT IF EXISTS outbox_type_check;
+ALTER TABLE outbox ADD CONSTRAINT outbox_type_check CHECK (type IN ('UserCreated', 'UserUpdated', 'UserBanned', 'UserUnbanned', 'UserDeleted'));
+
+ALTER TABLE webhooks DROP CONSTRAINT IF EXISTS webhooks_events_check;
+ALTER TABLE webhooks ADD CONSTRAINT webhooks_events_check CHECK (cardinality(events) > 0 AND events <@ '{created,updated,banned,unbanned,deleted}');
+
+ALTER TABLE webhook_deliveries DROP CONSTRAINT IF EXISTS webhook_deliveries_event_check;
+ALTER TABLE webhook_deliveries ADD CONSTRAINT webhook_deliveries_event_check CHECK (event IN ('created', 'updated', 'banned', 'unbanned', 'deleted', 'ping'));
@@ -0,0 +1,5 @@
+-- when an event may be sent next: later while a replica has claimed it or after the sink refused it, and until then
+-- it holds up the later events of its user but nobody else's
+ALTER TABLE outbox ADD COLUMN IF NOT EXISTS next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+CREATE INDEX IF NOT EXISTS outbox_user_id_idx ON outbox (user_id, id);
@@ -0,0 +1,204 @@
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::http::Request;
+use crate::jwt::Signer;
+use crate::repository::UserChanges;
+use crate::roles::{Permission, Role};
+use crate::users::User;
+
+// a key other services can call the API with, only the SHA-256 of the key is configured
+pub struct ApiKey
+{
+    pub name: String,
+    pub role: Role,
+    pub sha256: [u8; 32],
+}
+
+impl std::str::FromStr for ApiKey
+{
+    type Err = String;
+
+    // "name:role:sha256" with the digest in hex
+    fn from_str(value: &str) -> Result<Self, Self::Err>
+    {
+        let mut parts = value.trim().splitn(3, ':');
+
+        let (name, role, digest) = match (parts.next(), parts.next(), parts.next())
+        {
+            (Some(name), Some(role), Some(digest)) if !name.is_empty() => (name, role, digest),
+            _ => return Err(format!("'{}' is not name:role:sha256", value)),
+        };
+
+        // only built-in roles, the roles table is not available when the configuration is read
+        let role = Role::from_name(role).ok_or_else(|| format!("API key '{}' has an unknown role '{}', use user, moderator or admin", name, role))?;
+
+        let invalid = || format!("API key '{}' does not have a hex SHA-256", name);
+
+        if digest.len() != 64 || !digest.is_ascii()
+        {
+            return Err(invalid());
+        }
+
+        let mut sha256 = [0u8; 32];
+
+        for (i, byte) in sha256.iter_mut().enumerate()
+        {
+            *byte = u8::from_str_radix(&digest[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+
+        Ok(ApiKey { name: name.to_string(), role, sha256 })
+    }
+}
+
+// who is making a request
+pub struct Principal
+{
+    // None for API keys, they do not belong to a user
+    pub user_id: Option<Uuid>,
+
+    // the name of the API key, None for users
+    pub api_key: Option<String>,
+
+    pub permissions: Vec<Permission>,
+    pub banned: bool,
+}
+
+// the credentials sent with a request, Ok(None) when there are none
+pub fn authenticate(req: &Request, signer: &Signer, api_keys: &[ApiKey]) -> Result<Option<Principal>, ApiError>
+{
+    if let Some(key) = req.header("x-api-key")
+    {
+        let digest: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+
+        // every key is compared in full so the time taken does not depend on which one matched
+        let matched = api_keys.iter().fold(None, |matched, api_key| match constant_time_eq(&api_key.sha256, &digest)
+        {
+            true => Some(api_key),
+            false => matched,
+        });
+
+        return match matched
+        {
+            Some(api_key) =>
+            {
+                log::debug!("Request authenticated with API key '{}'", api_key.name);
+                Ok(Some(Principal { user_id: None, api_key: Some(api_key.name.clone()), permissions: api_key.role.permissions().to_vec(), banned: false }))
+            }
+            None => Err(ApiError::InvalidToken),
+        };
+    }
+
+    let authorization = match req.header("authorization")
+    {
+        Some(authorization) => authorization,
+        None => return Ok(None),
+    };
+
+    let token = match authorization.split_once(' ')
+    {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+        _ => return Err(ApiError::InvalidToken),
+    };
+
+    let claims = signer.verify(token).ok_or(ApiError::InvalidToken)?;
+
+    // permissions this binary does not know about grant nothing
+    let permissions = claims.permissions.iter().filter_map(|name| Permission::from_name(name)).collect();
+
+    Ok(Some(Principal { user_id: Some(claims.sub), api_key: None, permissions, banned: claims.banned }))
+}
+
+impl Principal
+{
+    fn is(&self, id: Uuid) -> bool
+    {
+        self.user_id == Some(id)
+    }
+
+    fn has(&self, permission: Permission) -> bool
+    {
+        self.permissions.contains(&permission)
+    }
+
+    // banned users can still sign in but not act
+    pub fn active(&self) -> Result<&Self, ApiError>
+    {
+        match self.banned
+        {
+            true => Err(ApiError::Banned),
+            false => Ok(self),
+        }
+    }
+
+    pub fn can_read(&self, id: Uuid) -> Result<(), ApiError>
+    {
+        allow(self.is(id) || self.has(Permission::ReadUsers))
+    }
+
+    pub fn can_list(&self) -> Result<(), ApiError>
+    {
+        allow(self.has(Permission::ListUsers))
+    }
+
+    pub fn can_delete(&self) -> Result<(), ApiError>
+    {
+        allow(self.has(Permission::DeleteUsers))
+    }
+
+    pub fn can_set_password(&self, id: Uuid) -> Result<(), ApiError>
+    {
+        allow(self.is(id) || self.has(Permission::EditUsers))
+    }
+
+    // sending another verification email goes with editing the email
+    pub fn can_resend_verification(&self, id: Uuid) -> Result<(), ApiError>
+    {
+        allow(self.is(id) || self.has(Permission::EditUsers))
+    }
+
+    pub fn can_manage_roles(&self) -> Result<(), ApiError>
+    {
+        allow(self.has(Permission::ManageRoles))
+    }
+
+    pub fn can_read_audit(&self) -> Result<(), ApiError>
+    {
+        allow(self.has(Permission::ReadAudit))
+    }
+
+    pub fn can_manage_webhooks(&self) -> Result<(), ApiError>
+    {
+        allow(self.has(Permission::ManageWebhooks))
+    }
+
+    // banning and lifting bans needs users:ban, and users:edit as well on oneself
+    pub fn can_ban(&self, id: Uuid) -> Result<(), ApiError>
+    {
+        allow(self.has(Permission::BanUsers) && (!self.is(id) || self.has(Permission::EditUsers)))
+    }
+
+    // everyone edits their own profile, others need users:edit
+    pub fn can_change(&self, current: &User, changes: &UserChanges) -> Result<(), ApiError>
+    {
+        let own = current.id.is_some_and(|id| self.is(id));
+        let profile = changes.name.as_ref().is_some_and(|name| *name != current.name) || changes.email.as_ref().is_some_and(|email| *email != current.email);
+
+        allow(!profile || own || self.has(Permission::EditUsers))
+    }
+}
+
+fn allow(allowed: bool) -> Result<(), ApiError>
+{
+    match allowed
+    {
+        true => Ok(()),
+        false => Err(ApiError::Forbidden),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool
+{
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
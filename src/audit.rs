@@ -0,0 +1,205 @@
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::access::Principal;
+use crate::error::ApiError;
+use crate::http::{Request, Response};
+use crate::pagination::AuditQuery;
+use crate::repository::AuditRepository;
+use crate::router::{Params, Query};
+use crate::timestamp;
+
+// fields that change along with every other change, they would only add noise to a diff
+const IGNORED: [&str; 1] = ["updated_at"];
+
+// who made a change
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Actor
+{
+    User { id: Uuid },
+    ApiKey { name: String },
+
+    // registration, nobody is signed in yet
+    Anonymous,
+
+    // background tasks such as the purge
+    System,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action
+{
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+    Purged,
+    PasswordChanged,
+    RoleAssigned,
+    RoleRevoked,
+    Banned,
+    Unbanned,
+    EmailVerified,
+}
+
+impl Action
+{
+    const ALL: [Action; 11] = [
+        Action::Created, Action::Updated, Action::Deleted, Action::Restored, Action::Purged,
+        Action::PasswordChanged, Action::RoleAssigned, Action::RoleRevoked, Action::Banned, Action::Unbanned, Action::EmailVerified,
+    ];
+
+    pub fn name(self) -> &'static str
+    {
+        match self
+        {
+            Action::Created => "created",
+            Action::Updated => "updated",
+            Action::Deleted => "deleted",
+            Action::Restored => "restored",
+            Action::Purged => "purged",
+            Action::PasswordChanged => "password_changed",
+            Action::RoleAssigned => "role_assigned",
+            Action::RoleRevoked => "role_revoked",
+            Action::Banned => "banned",
+            Action::Unbanned => "unbanned",
+            Action::EmailVerified => "email_verified",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action>
+    {
+        Action::ALL.into_iter().find(|action| action.name() == name)
+    }
+}
+
+// where a change comes from, every repository method that changes a user records it with one of these
+#[derive(Clone)]
+pub struct Audit
+{
+    pub actor: Actor,
+    pub request_id: Option<String>,
+    pub source_ip: Option<IpAddr>,
+}
+
+impl Audit
+{
+    pub fn new(principal: Option<&Principal>, request_id: &str, source_ip: IpAddr) -> Self
+    {
+        let actor = match principal
+        {
+            Some(Principal { user_id: Some(id), .. }) => Actor::User { id: *id },
+            Some(Principal { api_key: Some(name), .. }) => Actor::ApiKey { name: name.clone() },
+            _ => Actor::Anonymous,
+        };
+
+        Audit { actor, request_id: Some(request_id.to_string()), source_ip: Some(source_ip) }
+    }
+
+    pub fn system() -> Self
+    {
+        Audit { actor: Actor::System, request_id: None, source_ip: None }
+    }
+}
+
+// an entry of the audit log, entries are never changed or removed, not even when their user is purged
+#[derive(Clone, Serialize)]
+pub struct Entry
+{
+    pub id: i64,
+    pub actor: Actor,
+    pub action: Action,
+
+    // the user that was changed
+    pub user_id: Uuid,
+
+    // { "field": { "before": ..., "after": ... } } for every field that changed
+    pub changes: serde_json::Value,
+
+    pub request_id: Option<String>,
+    pub source_ip: Option<IpAddr>,
+
+    #[serde(with = "timestamp")]
+    pub created_at: SystemTime,
+}
+
+pub struct AuditPage
+{
+    pub entries: Vec<Entry>,
+
+    // link to the following page, None on the last one
+    pub next: Option<String>,
+}
+
+// the fields that differ between two versions of something, None stands for not existing
+pub fn diff<T: Serialize>(before: Option<&T>, after: Option<&T>) -> serde_json::Map<String, serde_json::Value>
+{
+    let fields = |value: Option<&T>| match value.map(serde_json::to_value)
+    {
+        Some(Ok(serde_json::Value::Object(fields))) => fields,
+        _ => serde_json::Map::new(),
+    };
+
+    let (before, after) = (fields(before), fields(after));
+
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).filter(|name| !IGNORED.contains(&name.as_str())).collect();
+
+    names.sort();
+    names.dedup();
+
+    // a missing field is the same as null
+    let null = serde_json::Value::Null;
+    let value = |fields: &serde_json::Map<String, serde_json::Value>, name: &str| fields.get(name).unwrap_or(&null).clone();
+
+    names.into_iter()
+        .filter(|name| value(&before, name) != value(&after, name))
+        .map(|name| (name.clone(), change(Some(value(&before, name)), Some(value(&after, name)))))
+        .collect()
+}
+
+// one entry of a diff
+pub fn change<T: Serialize>(before: Option<T>, after: Option<T>) -> serde_json::Value
+{
+    serde_json::json!({ "before": before, "after": after })
+}
+
+// the audit log of one user, newest first
+pub async fn handle_user_request(req: &Request, params: &Params, audit_log: &dyn AuditRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_read_audit()?;
+
+    let query = audit_query(req, Some(id))?;
+
+    list(&query, audit_log).await
+}
+
+// the audit log of every user, newest first
+pub async fn handle_list_request(req: &Request, audit_log: &dyn AuditRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    principal.active()?.can_read_audit()?;
+
+    let query = audit_query(req, None)?;
+
+    list(&query, audit_log).await
+}
+
+fn audit_query(req: &Request, user_id: Option<Uuid>) -> Result<AuditQuery, ApiError>
+{
+    let query = Query::parse(req.query.as_deref()).ok_or(ApiError::MalformedRequest("invalid percent-encoding in query string"))?;
+
+    AuditQuery::from_query(&query, &req.path, user_id)
+}
+
+async fn list(query: &AuditQuery, audit_log: &dyn AuditRepository) -> Result<Response, ApiError>
+{
+    let page = audit_log.audit_log(query).await?;
+
+    Ok(Response::json(200, serde_json::json!({ "data": page.entries, "next": page.next }).to_string()))
+}
@@ -0,0 +1,220 @@
+use std::time::{Duration, SystemTime};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::access::Principal;
+use crate::audit::Audit;
+use crate::error::ApiError;
+use crate::http::{Request, Response};
+use crate::jwt::Signer;
+use crate::password::{self, Hasher, Verified};
+use crate::repository::{CredentialRepository, NewRefreshToken, RoleRepository, Rotation, TokenRepository, UserRepository};
+use crate::router::Params;
+use crate::users::{parse_json, User};
+
+// everything needed to check passwords and hand out tokens
+pub struct Auth
+{
+    pub hasher: Hasher,
+    pub signer: Signer,
+    pub refresh_ttl: Duration,
+}
+
+#[derive(Deserialize)]
+struct PasswordChange
+{
+    // not needed while the user has no password yet
+    current_password: Option<String>,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+struct Login
+{
+    email: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct Refresh
+{
+    refresh_token: String,
+}
+
+// set or change the password of a user with the matching id
+pub async fn handle_password_request(req: &Request, params: &Params, users: &dyn UserRepository, credentials: &dyn CredentialRepository, hasher: &Hasher, audit: &Audit, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_set_password(id)?;
+
+    let change: PasswordChange = parse_json(req)?;
+
+    if let Some(error) = password::validate("new_password", &change.new_password)
+    {
+        return Err(ApiError::Validation(vec![error]));
+    }
+
+    users.get(id).await?.ok_or(ApiError::UserNotFound)?;
+
+    // an existing password can only be replaced by someone who knows it
+    if let Some(hash) = credentials.password_hash(id).await?
+    {
+        let current = change.current_password.unwrap_or_default();
+
+        if password::too_long(&current)
+        {
+            return Err(ApiError::InvalidCredentials);
+        }
+
+        if let Verified::Invalid = hasher.verify(&current, Some(hash)).await?
+        {
+            return Err(ApiError::InvalidCredentials);
+        }
+    }
+
+    let hash = hasher.hash(&change.new_password).await?;
+
+    // the user was deleted in the meantime
+    if !credentials.set_password_hash(id, &hash, Some(audit)).await?
+    {
+        return Err(ApiError::UserNotFound);
+    }
+
+    Ok(Response::new(204))
+}
+
+// check an email and password, answering with the user they belong to and a new pair of tokens
+pub async fn handle_login_request(req: &Request, users: &dyn UserRepository, credentials: &dyn CredentialRepository, tokens: &dyn TokenRepository, roles: &dyn RoleRepository, auth: &Auth) -> Result<Response, ApiError>
+{
+    let login: Login = parse_json(req)?;
+
+    if password::too_long(&login.password)
+    {
+        return Err(ApiError::InvalidCredentials);
+    }
+
+    let user = users.find_by_email(&login.email).await?;
+
+    let hash = match user.as_ref().and_then(|user| user.id)
+    {
+        Some(id) => credentials.password_hash(id).await?,
+        None => None,
+    };
+
+    // unknown emails and users without a password fail the same way as a wrong password
+    let user = match auth.hasher.verify(&login.password, hash).await?
+    {
+        Verified::Invalid => return Err(ApiError::InvalidCredentials),
+        Verified::Valid => user,
+        Verified::NeedsRehash =>
+        {
+            // the plain password is only available now, so this is the moment to upgrade the hash
+            if let Some(id) = user.as_ref().and_then(|user| user.id)
+            {
+                match auth.hasher.hash(&login.password).await
+                {
+                    Ok(hash) => { credentials.set_password_hash(id, &hash, None).await?; }
+                    Err(_) => log::warn!("Cannot rehash the password of user {}", id),
+                }
+            }
+
+            user
+        }
+    };
+
+    let user = user.ok_or(ApiError::InvalidCredentials)?;
+
+    // the user might have been deleted since the lookup
+    let user = users.record_login(user.id.unwrap_or_default()).await?.ok_or(ApiError::InvalidCredentials)?;
+    let refresh_token = new_token();
+
+    // a login starts a new family of refresh tokens
+    tokens.create_refresh_token(NewRefreshToken
+    {
+        hash: hash_token(&refresh_token),
+        user_id: user.id.unwrap_or_default(),
+        family: Uuid::new_v4(),
+        expires_at: SystemTime::now() + auth.refresh_ttl,
+    }).await?;
+
+    token_response(&user, &refresh_token, roles, auth).await
+}
+
+// exchange a refresh token for a new pair, the old refresh token cannot be used again
+pub async fn handle_refresh_request(req: &Request, users: &dyn UserRepository, tokens: &dyn TokenRepository, roles: &dyn RoleRepository, auth: &Auth) -> Result<Response, ApiError>
+{
+    let refresh: Refresh = parse_json(req)?;
+
+    let refresh_token = new_token();
+
+    let user_id = match tokens.rotate_refresh_token(&hash_token(&refresh.refresh_token), &hash_token(&refresh_token), SystemTime::now() + auth.refresh_ttl).await?
+    {
+        Rotation::Rotated(user_id) => user_id,
+        Rotation::Reused =>
+        {
+            log::warn!("A refresh token was used twice, its family has been revoked");
+            return Err(ApiError::InvalidToken);
+        }
+        Rotation::Invalid => return Err(ApiError::InvalidToken),
+    };
+
+    // the token carries the current roles and banned flag, not the ones at login
+    let user = users.get(user_id).await?.ok_or(ApiError::InvalidToken)?;
+
+    token_response(&user, &refresh_token, roles, auth).await
+}
+
+// revoke the refresh token and every token rotated from the same login
+pub async fn handle_logout_request(req: &Request, tokens: &dyn TokenRepository) -> Result<Response, ApiError>
+{
+    let refresh: Refresh = parse_json(req)?;
+
+    tokens.revoke_family(&hash_token(&refresh.refresh_token)).await?;
+
+    Ok(Response::new(204))
+}
+
+// the public keys that verify access tokens
+pub fn handle_jwks_request(auth: &Auth) -> Result<Response, ApiError>
+{
+    Ok(Response::json(200, auth.signer.jwks().to_string()).header("Cache-Control", "public, max-age=300"))
+}
+
+// OAuth 2.0 style token response (RFC 6749, section 5.1), never cached
+async fn token_response(user: &User, refresh_token: &str, roles: &dyn RoleRepository, auth: &Auth) -> Result<Response, ApiError>
+{
+    let permissions = roles.permissions(user.roles.as_deref().unwrap_or_default()).await?;
+
+    let body = serde_json::json!(
+    {
+        "user": user,
+        "access_token": auth.signer.access_token(user, permissions),
+        "token_type": "Bearer",
+        "expires_in": auth.signer.access_ttl().as_secs(),
+        "refresh_token": refresh_token,
+    });
+
+    Ok(Response::json(200, body.to_string()).header("Cache-Control", "no-store"))
+}
+
+// refresh and verification tokens are random, not JWTs, so they can only be checked against our own records
+pub fn new_token() -> String
+{
+    let mut bytes = [0u8; 32];
+
+    OsRng.fill_bytes(&mut bytes);
+
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// such tokens have enough entropy for a plain SHA-256, unlike passwords
+pub fn hash_token(token: &str) -> String
+{
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
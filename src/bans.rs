@@ -0,0 +1,174 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::access::Principal;
+use crate::audit::Audit;
+use crate::error::{ApiError, FieldError};
+use crate::http::{Request, Response};
+use crate::repository::{BanRepository, Lift, NewBan, UserRepository};
+use crate::router::Params;
+use crate::timestamp;
+use crate::users::parse_json;
+
+// longest accepted reason, in characters
+const MAX_REASON_LENGTH: usize = 1000;
+
+// a ban as kept in a user's history, lifted and expired bans are never deleted
+#[derive(Clone, Serialize)]
+pub struct Ban
+{
+    pub id: Uuid,
+    pub user_id: Uuid,
+
+    // None when the ban was made with an API key or the moderator has been deleted
+    pub moderator_id: Option<Uuid>,
+
+    pub reason: String,
+
+    #[serde(with = "timestamp")]
+    pub created_at: SystemTime,
+
+    // None for a permanent ban
+    #[serde(with = "timestamp::option")]
+    pub expires_at: Option<SystemTime>,
+
+    // when the ban was lifted or, for an expired ban, when it expired
+    #[serde(with = "timestamp::option")]
+    pub lifted_at: Option<SystemTime>,
+
+    // None when the ban expired or was lifted with an API key
+    pub lifted_by: Option<Uuid>,
+
+    // neither lifted nor expired, a user with an active ban is banned
+    pub active: bool,
+}
+
+impl Ban
+{
+    pub fn is_active(&self, now: SystemTime) -> bool
+    {
+        self.lifted_at.is_none() && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+
+    // the ban as it was before it was lifted, for the audit log
+    pub fn unlifted(&self) -> Ban
+    {
+        Ban { lifted_at: None, lifted_by: None, active: true, ..self.clone() }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BanRequest
+{
+    reason: String,
+
+    // defaults to the caller, only API keys may ban on behalf of a moderator
+    moderator_id: Option<Uuid>,
+
+    #[serde(default, with = "timestamp::option")]
+    expires_at: Option<SystemTime>,
+}
+
+// ban a user with the matching id, answering with the new ban
+pub async fn handle_create_request(req: &Request, params: &Params, users: &dyn UserRepository, bans: &dyn BanRepository, audit: &Audit, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_ban(id)?;
+
+    let request: BanRequest = parse_json(req)?;
+
+    let moderator_id = match (principal.user_id, request.moderator_id)
+    {
+        (Some(caller), Some(moderator_id)) if caller != moderator_id => return Err(ApiError::Validation(vec![FieldError::new("moderator_id", "not_caller", "Must be your own id, or left out.")])),
+        (caller, moderator_id) => moderator_id.or(caller),
+    };
+
+    let mut errors = Vec::new();
+    let reason = request.reason.trim().to_string();
+
+    match reason.chars().count()
+    {
+        0 => errors.push(FieldError::new("reason", "required", "Must not be empty.")),
+        length if length > MAX_REASON_LENGTH => errors.push(FieldError::new("reason", "too_long", &format!("Must be at most {} characters long.", MAX_REASON_LENGTH))),
+        _ => {}
+    }
+
+    if request.expires_at.is_some_and(|expires_at| expires_at <= SystemTime::now())
+    {
+        errors.push(FieldError::new("expires_at", "in_past", "Must be in the future."));
+    }
+
+    // a token can outlive the user it was issued to
+    if let Some(moderator_id) = moderator_id
+    {
+        if users.get(moderator_id).await?.is_none()
+        {
+            errors.push(FieldError::new("moderator_id", "not_found", "No user has this id."));
+        }
+    }
+
+    if !errors.is_empty()
+    {
+        return Err(ApiError::Validation(errors));
+    }
+
+    let ban = bans.create_ban(NewBan { user_id: id, moderator_id, reason, expires_at: request.expires_at }, audit).await?.ok_or(ApiError::UserNotFound)?;
+
+    Ok(Response::json(201, serde_json::to_string(&ban).unwrap()))
+}
+
+// every ban of a user with the matching id, newest first
+pub async fn handle_list_request(params: &Params, bans: &dyn BanRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_read(id)?;
+
+    let history = bans.bans(id).await?.ok_or(ApiError::UserNotFound)?;
+
+    Ok(Response::json(200, serde_json::json!({ "data": history }).to_string()))
+}
+
+// lift a ban before it expires, lifting a ban that is no longer active does nothing
+pub async fn handle_lift_request(params: &Params, bans: &dyn BanRepository, audit: &Audit, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+    let ban_id: Uuid = params.get("ban_id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_ban(id)?;
+
+    match bans.lift_ban(id, ban_id, principal.user_id, audit).await?
+    {
+        Lift::Lifted | Lift::Inactive => {}
+        Lift::NotFound => return Err(ApiError::BanNotFound),
+    }
+
+    Ok(Response::new(204))
+}
+
+// periodically mark expired bans as lifted so their end shows up in the history and as an event,
+// users stop counting as banned the moment a ban expires whether or not this has run
+pub fn spawn_expiry_task(bans: Arc<dyn BanRepository>, interval: Duration)
+{
+    tokio::spawn(async move
+    {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop
+        {
+            ticker.tick().await;
+
+            match bans.lift_expired(&Audit::system()).await
+            {
+                Ok(expired) if expired.is_empty() => {}
+                Ok(expired) => log::info!("Lifted {} expired bans", expired.len()),
+                Err(e) => log::warn!("Cannot lift expired bans: {:?}", e),
+            }
+        }
+    });
+}
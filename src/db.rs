@@ -0,0 +1,29 @@
+use std::time::Duration;
+
+use bb8_postgres::PostgresConnectionManager;
+use tokio_postgres::NoTls;
+
+pub type Pool = bb8::Pool<PostgresConnectionManager<NoTls>>;
+
+pub struct PoolOptions
+{
+    pub min_size: u32,
+    pub max_size: u32,
+    pub acquire_timeout: Duration,
+}
+
+// create the connection pool shared by all handlers
+pub async fn create_pool(db_url: &str, options: &PoolOptions) -> Result<Pool, tokio_postgres::Error>
+{
+    let manager = PostgresConnectionManager::new_from_stringlike(db_url, NoTls)?;
+
+    // connections are checked before being handed out, broken ones are dropped and replaced,
+    // which is what gets the service going again after the database restarts
+    bb8::Pool::builder()
+        .min_idle(options.min_size)
+        .max_size(options.max_size)
+        .connection_timeout(options.acquire_timeout)
+        .test_on_check_out(true)
+        .build(manager)
+        .await
+}
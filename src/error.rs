@@ -0,0 +1,259 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::json;
+use tokio_postgres::error::SqlState;
+
+use crate::http::{self, Response};
+
+// patch formats understood by PATCH endpoints
+pub const ACCEPT_PATCH: &str = "application/merge-patch+json, application/json-patch+json";
+
+// every error a handler can return, rendered as application/problem+json (RFC 7807)
+#[derive(Debug)]
+pub enum ApiError
+{
+    MalformedRequest(&'static str),
+    InvalidJson(String),
+    InvalidId,
+    InvalidQuery(String),
+    InvalidPatch(String),
+    InvalidVerificationToken,
+    InvalidResetToken,
+    PatchTestFailed(String),
+    UnsupportedPatchType,
+    Validation(Vec<FieldError>),
+    InvalidCredentials,
+    InvalidToken,
+    AuthenticationRequired,
+    Forbidden,
+    Banned,
+    RouteNotFound,
+    UserNotFound,
+    RoleNotFound,
+    BanNotFound,
+    WebhookNotFound,
+    MethodNotAllowed(String),
+    DuplicateEmail,
+    NotDeleted,
+    RestoreExpired,
+    EmailAlreadyVerified,
+    PayloadTooLarge,
+    HeadersTooLarge,
+    RequestTimeout,
+
+    // how long to wait before trying again
+    TooManyRequests(Duration),
+
+    DatabaseUnavailable,
+    Internal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldError
+{
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldError
+{
+    pub fn new(field: &str, code: &'static str, message: &str) -> Self
+    {
+        FieldError { field: field.to_string(), code, message: message.to_string() }
+    }
+}
+
+impl ApiError
+{
+    pub fn status(&self) -> u16
+    {
+        match self
+        {
+            ApiError::MalformedRequest(_) | ApiError::InvalidJson(_) | ApiError::InvalidId | ApiError::InvalidQuery(_) | ApiError::InvalidPatch(_) | ApiError::InvalidVerificationToken | ApiError::InvalidResetToken => 400,
+            ApiError::InvalidCredentials | ApiError::InvalidToken | ApiError::AuthenticationRequired => 401,
+            ApiError::Forbidden | ApiError::Banned => 403,
+            ApiError::RouteNotFound | ApiError::UserNotFound | ApiError::RoleNotFound | ApiError::BanNotFound | ApiError::WebhookNotFound => 404,
+            ApiError::MethodNotAllowed(_) => 405,
+            ApiError::RequestTimeout => 408,
+            ApiError::DuplicateEmail | ApiError::PatchTestFailed(_) | ApiError::NotDeleted | ApiError::EmailAlreadyVerified => 409,
+            ApiError::RestoreExpired => 410,
+            ApiError::PayloadTooLarge => 413,
+            ApiError::UnsupportedPatchType => 415,
+            ApiError::Validation(_) => 422,
+            ApiError::TooManyRequests(_) => 429,
+            ApiError::HeadersTooLarge => 431,
+            ApiError::Internal => 500,
+            ApiError::DatabaseUnavailable => 503,
+        }
+    }
+
+    // machine-readable code, stable across releases
+    pub fn code(&self) -> &'static str
+    {
+        match self
+        {
+            ApiError::MalformedRequest(_) => "malformed_request",
+            ApiError::InvalidJson(_) => "invalid_json",
+            ApiError::InvalidId => "invalid_id",
+            ApiError::InvalidQuery(_) => "invalid_query",
+            ApiError::InvalidPatch(_) => "invalid_patch",
+            ApiError::InvalidVerificationToken => "invalid_verification_token",
+            ApiError::InvalidResetToken => "invalid_reset_token",
+            ApiError::PatchTestFailed(_) => "patch_test_failed",
+            ApiError::UnsupportedPatchType => "unsupported_patch_type",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::AuthenticationRequired => "authentication_required",
+            ApiError::Forbidden => "forbidden",
+            ApiError::Banned => "user_banned",
+            ApiError::RouteNotFound => "route_not_found",
+            ApiError::UserNotFound => "user_not_found",
+            ApiError::RoleNotFound => "role_not_found",
+            ApiError::BanNotFound => "ban_not_found",
+            ApiError::WebhookNotFound => "webhook_not_found",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
+            ApiError::DuplicateEmail => "email_taken",
+            ApiError::NotDeleted => "user_not_deleted",
+            ApiError::RestoreExpired => "restore_expired",
+            ApiError::EmailAlreadyVerified => "email_already_verified",
+            ApiError::PayloadTooLarge => "payload_too_large",
+            ApiError::HeadersTooLarge => "headers_too_large",
+            ApiError::RequestTimeout => "request_timeout",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::DatabaseUnavailable => "database_unavailable",
+            ApiError::Internal => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> String
+    {
+        match self
+        {
+            ApiError::MalformedRequest(reason) => format!("Malformed request: {}.", reason),
+            ApiError::InvalidJson(reason) => format!("Invalid JSON: {}.", reason),
+            ApiError::InvalidId => "The id in the path is not a valid UUID.".to_string(),
+            ApiError::InvalidQuery(reason) => format!("Invalid query string: {}.", reason),
+            ApiError::InvalidPatch(reason) => format!("Invalid patch: {}.", reason),
+            ApiError::InvalidVerificationToken => "The verification token is invalid, expired or already used.".to_string(),
+            ApiError::InvalidResetToken => "The password reset token is invalid, expired or already used.".to_string(),
+            ApiError::PatchTestFailed(reason) => format!("Patch test failed: {}.", reason),
+            ApiError::UnsupportedPatchType => format!("Patches must be sent as one of: {}.", ACCEPT_PATCH),
+            ApiError::Validation(_) => "One or more fields are invalid.".to_string(),
+            ApiError::InvalidCredentials => "The credentials are incorrect.".to_string(),
+            ApiError::InvalidToken => "The token is invalid, expired or revoked.".to_string(),
+            ApiError::AuthenticationRequired => "Authenticate with a bearer token or an API key.".to_string(),
+            ApiError::Forbidden => "You are not allowed to do this.".to_string(),
+            ApiError::Banned => "Banned users cannot do this.".to_string(),
+            ApiError::RouteNotFound => "No endpoint exists at this path.".to_string(),
+            ApiError::UserNotFound => "User not found.".to_string(),
+            ApiError::RoleNotFound => "Role not found.".to_string(),
+            ApiError::BanNotFound => "Ban not found.".to_string(),
+            ApiError::WebhookNotFound => "Webhook not found.".to_string(),
+            ApiError::MethodNotAllowed(allow) => format!("Method not allowed, use one of: {}.", allow),
+            ApiError::DuplicateEmail => "Email already exists.".to_string(),
+            ApiError::NotDeleted => "The user is not deleted.".to_string(),
+            ApiError::RestoreExpired => "The user was deleted too long ago to be restored.".to_string(),
+            ApiError::EmailAlreadyVerified => "The email is verified already.".to_string(),
+            ApiError::PayloadTooLarge => "Request body too large.".to_string(),
+            ApiError::HeadersTooLarge => "Request headers too large.".to_string(),
+            ApiError::RequestTimeout => "The request was not received in time.".to_string(),
+            ApiError::TooManyRequests(wait) => format!("Too many requests, try again in {} seconds.", whole_seconds(*wait)),
+            ApiError::DatabaseUnavailable => "The database is currently unavailable, try again later.".to_string(),
+            ApiError::Internal => "Internal server error.".to_string(),
+        }
+    }
+
+    pub fn into_response(self, instance: &str, request_id: &str) -> Response
+    {
+        let status = self.status();
+
+        let mut problem = json!(
+        {
+            "type": "about:blank",
+            "title": http::reason_phrase(status),
+            "status": status,
+            "detail": self.message(),
+            "instance": instance,
+            "code": self.code(),
+            "request_id": request_id,
+        });
+
+        if let ApiError::Validation(errors) = &self
+        {
+            problem["errors"] = json!(errors);
+        }
+
+        let response = Response::new(status).header("Content-Type", "application/problem+json").body(problem.to_string());
+
+        match self
+        {
+            ApiError::MethodNotAllowed(allow) => response.header("Allow", &allow),
+            ApiError::UnsupportedPatchType => response.header("Accept-Patch", ACCEPT_PATCH),
+            ApiError::AuthenticationRequired => response.header("WWW-Authenticate", "Bearer"),
+            ApiError::InvalidToken => response.header("WWW-Authenticate", "Bearer error=\"invalid_token\""),
+            ApiError::TooManyRequests(wait) => response.header("Retry-After", &whole_seconds(wait).to_string()),
+            _ => response,
+        }
+    }
+}
+
+// rounded up, waiting less than asked for would only be refused again
+fn whole_seconds(wait: Duration) -> u64
+{
+    (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).max(1)
+}
+
+impl From<tokio_postgres::Error> for ApiError
+{
+    fn from(e: tokio_postgres::Error) -> Self
+    {
+        match e.code()
+        {
+            Some(code) if *code == SqlState::UNIQUE_VIOLATION && e.as_db_error().and_then(|db| db.constraint()) == Some("users_email_key") => ApiError::DuplicateEmail,
+
+            Some(code) if [SqlState::ADMIN_SHUTDOWN, SqlState::CRASH_SHUTDOWN, SqlState::CANNOT_CONNECT_NOW].contains(code) =>
+            {
+                log::warn!("DB is shutting down: {}", e);
+                ApiError::DatabaseUnavailable
+            }
+            None if e.is_closed() =>
+            {
+                log::warn!("DB connection error: {}", e);
+                ApiError::DatabaseUnavailable
+            }
+            _ =>
+            {
+                log::error!("DB error: {}", e);
+                ApiError::Internal
+            }
+        }
+    }
+}
+
+impl From<bb8::RunError<tokio_postgres::Error>> for ApiError
+{
+    fn from(e: bb8::RunError<tokio_postgres::Error>) -> Self
+    {
+        log::warn!("Cannot acquire a DB connection: {}", e);
+        ApiError::DatabaseUnavailable
+    }
+}
+
+impl From<http::RequestError> for ApiError
+{
+    fn from(e: http::RequestError) -> Self
+    {
+        match e
+        {
+            http::RequestError::PayloadTooLarge => ApiError::PayloadTooLarge,
+            http::RequestError::HeadersTooLarge => ApiError::HeadersTooLarge,
+            http::RequestError::Malformed(reason) => ApiError::MalformedRequest(reason),
+
+            // I/O errors close the connection instead of producing a response
+            http::RequestError::Io(_) => ApiError::Internal,
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+use crate::access::Principal;
+use crate::error::ApiError;
+use crate::http::{Request, Response};
+use crate::outbox::{Kind, OutboxEvent};
+use crate::router::Query;
+
+// how long browsers wait before reconnecting after the stream ends
+const RETRY_MILLISECONDS: u64 = 3000;
+
+// messages waiting to be written to one client, it is disconnected when it cannot keep up
+const CLIENT_BUFFER: usize = 64;
+
+// committed events in the order they were committed, for clients following them live with GET /users/events;
+// they come from the memory store directly, or from every replica through the listener with PostgreSQL
+#[derive(Clone)]
+pub struct Feed
+{
+    shared: Arc<Shared>,
+}
+
+struct Shared
+{
+    state: Mutex<State>,
+    capacity: usize,
+}
+
+struct State
+{
+    sender: broadcast::Sender<Arc<OutboxEvent>>,
+
+    // the latest events, oldest first, so a client that reconnects gets what it missed
+    recent: VecDeque<Arc<OutboxEvent>>,
+}
+
+pub struct Subscription
+{
+    // the buffered events after the one the client saw last
+    pub missed: Vec<Arc<OutboxEvent>>,
+
+    // the buffer does not hold the event the client saw last, so some may be lost
+    pub gap: bool,
+
+    pub receiver: broadcast::Receiver<Arc<OutboxEvent>>,
+}
+
+impl Feed
+{
+    pub fn new(capacity: usize) -> Self
+    {
+        let state = State { sender: broadcast::channel(capacity).0, recent: VecDeque::with_capacity(capacity) };
+
+        Feed { shared: Arc::new(Shared { state: Mutex::new(state), capacity }) }
+    }
+
+    // only called once the change the event announces is committed
+    pub fn publish(&self, event: OutboxEvent)
+    {
+        let event = Arc::new(event);
+
+        // sent under the lock so a subscriber never gets an event both from the buffer and live
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.recent.len() == self.shared.capacity
+        {
+            state.recent.pop_front();
+        }
+
+        state.recent.push_back(event.clone());
+
+        // an error only means there are no subscribers
+        let _ = state.sender.send(event);
+    }
+
+    // events may have been missed, so the buffer cannot be trusted anymore: the current streams end
+    // and the clients that come back are told to reload
+    pub fn restart(&self)
+    {
+        let mut state = self.shared.state.lock().unwrap();
+
+        state.recent.clear();
+        state.sender = broadcast::channel(self.shared.capacity).0;
+    }
+
+    // follow the events from now on, after the buffered ones that came after last_id
+    pub fn subscribe(&self, last_id: Option<&str>) -> Subscription
+    {
+        let state = self.shared.state.lock().unwrap();
+        let receiver = state.sender.subscribe();
+
+        // ids are only compared as they were sent, one from before a restart is simply not found
+        let (missed, gap) = match last_id.map(|id| state.recent.iter().position(|event| event.id.to_string() == id.trim()))
+        {
+            None => (Vec::new(), false),
+            Some(Some(seen)) => (state.recent.iter().skip(seen + 1).cloned().collect(), false),
+            Some(None) => (state.recent.iter().cloned().collect(), true),
+        };
+
+        Subscription { missed, gap, receiver }
+    }
+}
+
+// which events a client asked for
+struct Filter
+{
+    kinds: Option<Vec<Kind>>,
+    user_id: Option<Uuid>,
+}
+
+impl Filter
+{
+    fn from_query(query: &Query) -> Result<Self, ApiError>
+    {
+        let kinds = match query.get::<String>("type").unwrap_or_default()
+        {
+            Some(names) => Some(names.split(',').map(|name| Kind::from_name(name.trim()).ok_or_else(||
+            {
+                ApiError::InvalidQuery(format!("'{}' is not a valid type, expected UserCreated, UserUpdated, UserBanned, UserUnbanned or UserDeleted", name))
+            })).collect::<Result<Vec<Kind>, ApiError>>()?),
+            None => None,
+        };
+
+        let user_id = query.get("user_id").map_err(|value| ApiError::InvalidQuery(format!("'{}' is not a valid user_id, expected a UUID", value)))?;
+
+        Ok(Filter { kinds, user_id })
+    }
+
+    fn matches(&self, event: &OutboxEvent) -> bool
+    {
+        self.kinds.as_ref().is_none_or(|kinds| kinds.contains(&event.kind)) && self.user_id.is_none_or(|id| event.user_id == id)
+    }
+}
+
+// stream the changes to users as server-sent events until the client goes away
+pub fn handle_stream_request(req: &Request, feed: &Feed, heartbeat_interval: Duration, principal: &Principal) -> Result<Response, ApiError>
+{
+    // the stream shows every user, like the list does
+    principal.active()?.can_list()?;
+
+    let query = Query::parse(req.query.as_deref()).ok_or(ApiError::MalformedRequest("invalid percent-encoding in query string"))?;
+    let filter = Filter::from_query(&query)?;
+
+    let subscription = feed.subscribe(req.header("last-event-id"));
+
+    let (sender, receiver) = mpsc::channel(CLIENT_BUFFER);
+
+    tokio::spawn(stream(subscription, filter, heartbeat_interval, sender));
+
+    Ok(Response::new(200)
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .stream(receiver))
+}
+
+// write the events to the connection through the sender, ending when the connection does
+async fn stream(mut subscription: Subscription, filter: Filter, heartbeat_interval: Duration, sender: mpsc::Sender<Vec<u8>>)
+{
+    let mut first = format!("retry: {}\n\n", RETRY_MILLISECONDS);
+
+    // the client has to reload what it shows, the events cannot tell it what changed meanwhile
+    if subscription.gap
+    {
+        first.push_str("event: reset\ndata: {}\n\n");
+    }
+
+    if sender.send(first.into_bytes()).await.is_err()
+    {
+        return;
+    }
+
+    for event in subscription.missed.iter().filter(|event| filter.matches(event))
+    {
+        if sender.send(message(event)).await.is_err()
+        {
+            return;
+        }
+    }
+
+    // comments keep proxies from closing an idle connection and reveal clients that are gone
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + heartbeat_interval, heartbeat_interval);
+
+    loop
+    {
+        let chunk = tokio::select!
+        {
+            received = subscription.receiver.recv() => match received
+            {
+                Ok(event) if filter.matches(&event) => message(&event),
+                Ok(_) => continue,
+
+                // the client fell behind or the feed restarted, closing makes it reconnect and resume from the buffer
+                Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => return,
+            },
+            _ = heartbeat.tick() => b": heartbeat\n\n".to_vec(),
+        };
+
+        // a full buffer means the client stopped reading
+        if sender.try_send(chunk).is_err()
+        {
+            return;
+        }
+    }
+}
+
+// the event as it is sent to the outbox sink, named after its type
+fn message(event: &OutboxEvent) -> Vec<u8>
+{
+    format!("id: {}\nevent: {}\ndata: {}\n\n", event.id, event.kind.name(), serde_json::to_string(event).unwrap()).into_bytes()
+}
@@ -0,0 +1,559 @@
+use std::fmt;
+use std::num::IntErrorKind;
+use std::str::FromStr;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+// upper bound for the request line and headers combined
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+
+pub struct Request
+{
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: String,
+
+    // header names are stored lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request
+{
+    // get the first header with the given name (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str>
+    {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    // HTTP/1.1 connections persist unless the client says otherwise, HTTP/1.0 ones only on request
+    pub fn keep_alive(&self) -> bool
+    {
+        let connection = self.header("connection").unwrap_or_default().to_ascii_lowercase();
+        let has_option = |option: &str| connection.split(',').any(|value| value.trim() == option);
+
+        match self.version.as_str()
+        {
+            "HTTP/1.0" => has_option("keep-alive"),
+            _ => !has_option("close"),
+        }
+    }
+}
+
+pub struct Response
+{
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+
+    // written after the body as it comes, until the sender is dropped or the client goes away
+    pub stream: Option<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl Response
+{
+    pub fn new(status: u16) -> Self
+    {
+        Response { status, headers: Vec::new(), body: Vec::new(), stream: None }
+    }
+
+    pub fn json(status: u16, body: String) -> Self
+    {
+        Response::new(status).header("Content-Type", "application/json").body(body)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self
+    {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: String) -> Self
+    {
+        self.body = body.into_bytes();
+        self
+    }
+
+    pub fn stream(mut self, receiver: mpsc::Receiver<Vec<u8>>) -> Self
+    {
+        self.stream = Some(receiver);
+        self
+    }
+
+    // serialize the response, Content-Length is sent so the connection can be reused,
+    // except for a streamed body which has no length and ends with the connection
+    pub async fn write_to<W>(self, stream: &mut W, head_only: bool, keep_alive: bool) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let keep_alive = keep_alive && self.stream.is_none();
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+
+        for (name, value) in &self.headers
+        {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        // 1xx, 204 and 304 responses never carry a body or its length
+        if self.status >= 200 && self.status != 204 && self.status != 304 && self.stream.is_none()
+        {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+
+        head.push_str(if keep_alive { "Connection: keep-alive\r\n\r\n" } else { "Connection: close\r\n\r\n" });
+
+        stream.write_all(head.as_bytes()).await?;
+
+        if !head_only
+        {
+            stream.write_all(&self.body).await?;
+        }
+
+        stream.flush().await?;
+
+        if let (Some(mut chunks), false) = (self.stream, head_only)
+        {
+            while let Some(chunk) = chunks.recv().await
+            {
+                stream.write_all(&chunk).await?;
+                stream.flush().await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str
+{
+    match status
+    {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+
+        _ => "Unknown",
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestError
+{
+    Io(std::io::Error),
+    Malformed(&'static str),
+    HeadersTooLarge,
+    PayloadTooLarge,
+}
+
+impl fmt::Display for RequestError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            RequestError::Io(e) => write!(f, "I/O error: {}", e),
+            RequestError::Malformed(reason) => write!(f, "malformed request: {}", reason),
+            RequestError::HeadersTooLarge => write!(f, "request headers too large"),
+            RequestError::PayloadTooLarge => write!(f, "request body too large"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<std::io::Error> for RequestError
+{
+    fn from(e: std::io::Error) -> Self
+    {
+        RequestError::Io(e)
+    }
+}
+
+// read a single request from the stream, returns None if the connection was closed before a request started
+pub async fn read_request<S>(stream: &mut S, max_body_size: usize) -> Result<Option<Request>, RequestError>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let mut header_size = 0;
+
+    let request_line = match read_line(stream, &mut header_size).await?
+    {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let mut parts = request_line.split_whitespace();
+
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next())
+    {
+        (Some(method), Some(target), Some(version), None) => (method.to_string(), target.to_string(), version.to_string()),
+        _ => return Err(RequestError::Malformed("invalid request line")),
+    };
+
+    if !version.starts_with("HTTP/1.")
+    {
+        return Err(RequestError::Malformed("unsupported HTTP version"));
+    }
+
+    let headers = read_headers(stream, &mut header_size).await?;
+
+    let (path, query) = match target.split_once('?')
+    {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target, None),
+    };
+
+    let mut request = Request { method, path, query, version, headers, body: Vec::new() };
+
+    let chunked = request.header("transfer-encoding").map(|value| value.to_ascii_lowercase().contains("chunked")).unwrap_or(false);
+
+    // repeated headers and lists ("5, 5") are only accepted when every value is the same (RFC 9112 section 6.3),
+    // a proxy in front could pick a different one and see another request in the body (request smuggling)
+    let mut content_length = None;
+
+    for value in request.headers.iter().filter(|(key, _)| key.eq_ignore_ascii_case("content-length")).flat_map(|(_, value)| value.split(','))
+    {
+        let length = value.trim().parse::<usize>().map_err(|_| RequestError::Malformed("invalid Content-Length"))?;
+
+        if content_length.is_some_and(|seen| seen != length)
+        {
+            return Err(RequestError::Malformed("conflicting Content-Length values"));
+        }
+
+        content_length = Some(length);
+    }
+
+    // a message with both framings is ambiguous (request smuggling), so refuse it
+    if chunked && content_length.is_some()
+    {
+        return Err(RequestError::Malformed("both Content-Length and Transfer-Encoding present"));
+    }
+
+    if content_length.is_some_and(|length| length > max_body_size)
+    {
+        return Err(RequestError::PayloadTooLarge);
+    }
+
+    let has_body = chunked || content_length.is_some_and(|length| length > 0);
+
+    // let the client know it can go ahead with the body
+    if has_body && request.header("expect").is_some_and(|value| value.eq_ignore_ascii_case("100-continue"))
+    {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        stream.flush().await?;
+    }
+
+    if chunked
+    {
+        request.body = read_chunked_body(stream, max_body_size).await?;
+    }
+    else if let Some(length) = content_length
+    {
+        let mut body = vec![0; length];
+
+        stream.read_exact(&mut body).await.map_err(truncated)?;
+
+        request.body = body;
+    }
+
+    Ok(Some(request))
+}
+
+async fn read_headers<S>(stream: &mut S, header_size: &mut usize) -> Result<Vec<(String, String)>, RequestError>
+where
+    S: AsyncBufRead + Unpin,
+{
+    let mut headers = Vec::new();
+
+    loop
+    {
+        let line = read_line(stream, header_size).await?.ok_or(RequestError::Malformed("connection closed inside headers"))?;
+
+        // an empty line ends the header section
+        if line.is_empty()
+        {
+            return Ok(headers);
+        }
+
+        let (name, value) = line.split_once(':').ok_or(RequestError::Malformed("invalid header line"))?;
+
+        if name.is_empty() || name.ends_with(char::is_whitespace)
+        {
+            return Err(RequestError::Malformed("invalid header name"));
+        }
+
+        headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+    }
+}
+
+async fn read_chunked_body<S>(stream: &mut S, max_body_size: usize) -> Result<Vec<u8>, RequestError>
+where
+    S: AsyncBufRead + Unpin,
+{
+    let mut body = Vec::new();
+
+    // chunk metadata counts against the header limit so a client cannot stream endless extensions or trailers
+    let mut metadata_size = 0;
+
+    loop
+    {
+        let line = read_line(stream, &mut metadata_size).await?.ok_or(RequestError::Malformed("connection closed inside chunked body"))?;
+
+        // ignore chunk extensions
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|e| match e.kind()
+        {
+            IntErrorKind::PosOverflow => RequestError::PayloadTooLarge,
+            _ => RequestError::Malformed("invalid chunk size"),
+        })?;
+
+        if size == 0
+        {
+            break;
+        }
+
+        // the body never exceeds the limit, so this cannot overflow however large the size is
+        if size > max_body_size - body.len()
+        {
+            return Err(RequestError::PayloadTooLarge);
+        }
+
+        let start = body.len();
+
+        body.resize(start + size, 0);
+        stream.read_exact(&mut body[start..]).await.map_err(truncated)?;
+
+        // every chunk is followed by CRLF
+        let mut crlf = [0; 2];
+
+        stream.read_exact(&mut crlf).await.map_err(truncated)?;
+
+        if &crlf != b"\r\n"
+        {
+            return Err(RequestError::Malformed("missing CRLF after chunk"));
+        }
+    }
+
+    // trailers are read and discarded
+    read_headers(stream, &mut metadata_size).await?;
+
+    Ok(body)
+}
+
+// read a CRLF (or bare LF) terminated line, returns None on EOF before any byte was read
+async fn read_line<S>(stream: &mut S, size: &mut usize) -> Result<Option<String>, RequestError>
+where
+    S: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+
+    // never buffer more than the remaining header budget
+    let remaining = (MAX_HEADER_SIZE - *size) as u64;
+    let n = (&mut *stream).take(remaining + 1).read_until(b'\n', &mut line).await?;
+
+    if n == 0
+    {
+        return Ok(None);
+    }
+
+    *size += n;
+
+    if *size > MAX_HEADER_SIZE
+    {
+        return Err(RequestError::HeadersTooLarge);
+    }
+
+    if line.last() != Some(&b'\n')
+    {
+        return Err(RequestError::Malformed("connection closed inside line"));
+    }
+
+    line.pop();
+
+    if line.last() == Some(&b'\r')
+    {
+        line.pop();
+    }
+
+    String::from_utf8(line).map(Some).map_err(|_| RequestError::Malformed("header is not valid UTF-8"))
+}
+
+// scheme://[user:password@]host[:port][/path] for the services this one talks to, there is no TLS so no https
+#[derive(Clone)]
+pub struct Url
+{
+    pub scheme: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub host: String,
+    pub port: u16,
+
+    // with the query string, "/" when the URL has none
+    pub path: String,
+}
+
+impl FromStr for Url
+{
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err>
+    {
+        let (scheme, rest) = value.split_once("://").ok_or_else(|| format!("'{}' is not a URL", value))?;
+
+        let default_port = match scheme
+        {
+            "http" => 80,
+            "redis" => 6379,
+            "smtp" => 25,
+            _ => return Err(format!("'{}' is not an http://, redis:// or smtp:// URL", value)),
+        };
+
+        let (authority, path) = match rest.find(['/', '?'])
+        {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+
+        // redis://:password@host has a password without a user
+        let (username, password, address) = match authority.rsplit_once('@')
+        {
+            Some((credentials, address)) => match credentials.split_once(':')
+            {
+                Some((username, password)) => (Some(username.to_string()).filter(|username| !username.is_empty()), Some(password.to_string()), address),
+                None => (None, Some(credentials.to_string()), address),
+            },
+            None => (None, None, authority),
+        };
+
+        let (host, port) = match address.rsplit_once(':')
+        {
+            Some((host, port)) => (host, port.parse().map_err(|_| format!("'{}' has an invalid port", value))?),
+            None => (address, default_port),
+        };
+
+        if host.is_empty()
+        {
+            return Err(format!("'{}' has no host", value));
+        }
+
+        Ok(Url { scheme: scheme.to_string(), username, password, host: host.to_string(), port, path: path.to_string() })
+    }
+}
+
+// POST a body to an http:// URL on a connection of its own, answering with the status code, the response body is not read
+pub async fn post(url: &Url, headers: &[(&str, String)], body: &[u8]) -> std::io::Result<u16>
+{
+    let mut stream = BufReader::new(TcpStream::connect((url.host.as_str(), url.port)).await?);
+
+    let mut head = format!("POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Length: {}\r\nConnection: close\r\n", url.path, url.host, url.port, body.len());
+
+    for (name, value) in headers
+    {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+
+    // HTTP/1.1 204 No Content
+    let mut status_line = String::new();
+
+    (&mut stream).take(MAX_HEADER_SIZE as u64).read_line(&mut status_line).await?;
+
+    match status_line.split_whitespace().nth(1).and_then(|status| status.parse().ok())
+    {
+        Some(status) if status_line.starts_with("HTTP/1.") => Ok(status),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid HTTP response")),
+    }
+}
+
+fn truncated(e: std::io::Error) -> RequestError
+{
+    match e.kind()
+    {
+        std::io::ErrorKind::UnexpectedEof => RequestError::Malformed("body shorter than declared"),
+        _ => RequestError::Io(e),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::io::Cursor;
+
+    use super::*;
+
+    async fn read(raw: &str, max_body_size: usize) -> Result<Option<Request>, RequestError>
+    {
+        read_request(&mut Cursor::new(raw.as_bytes().to_vec()), max_body_size).await
+    }
+
+    #[tokio::test]
+    async fn reads_a_chunked_body()
+    {
+        let request = read("POST /users HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;ext=1\r\nde\r\n0\r\n\r\n", 16).await.unwrap().unwrap();
+
+        assert_eq!(request.body, b"abcde");
+    }
+
+    #[tokio::test]
+    async fn refuses_chunks_over_the_limit()
+    {
+        let result = read("POST /users HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n", 4).await;
+
+        assert!(matches!(result, Err(RequestError::PayloadTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn refuses_a_huge_chunk_size_without_overflowing()
+    {
+        for size in ["ffffffffffffffff", "fffffffffffffffffffff"]
+        {
+            let raw = format!("POST /users HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\n{}\r\nb\r\n0\r\n\r\n", size);
+
+            assert!(matches!(read(&raw, 1024).await, Err(RequestError::PayloadTooLarge)), "chunk size {}", size);
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_repeated_content_lengths_that_agree()
+    {
+        let request = read("POST /users HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3, 3\r\n\r\nabc", 16).await.unwrap().unwrap();
+
+        assert_eq!(request.body, b"abc");
+    }
+
+    #[tokio::test]
+    async fn refuses_conflicting_content_lengths()
+    {
+        for headers in ["Content-Length: 3\r\nContent-Length: 5", "Content-Length: 3, 5", "content-length: 5\r\nContent-Length: 3"]
+        {
+            let result = read(&format!("POST /users HTTP/1.1\r\n{}\r\n\r\nabcde", headers), 16).await;
+
+            assert!(matches!(result, Err(RequestError::Malformed(_))), "{}", headers);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ed25519_dalek::pkcs8::DecodePrivateKey;
+use ed25519_dalek::{Signature, Signer as _, SigningKey, VerifyingKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::users::User;
+
+// what other services (and this one) learn about the caller from an access token
+#[derive(Serialize, Deserialize)]
+pub struct Claims
+{
+    pub iss: String,
+    pub sub: Uuid,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+    pub banned: bool,
+    pub iat: u64,
+    pub exp: u64,
+}
+
+// issues EdDSA (Ed25519) access tokens, the newest key signs and every key is published
+// so tokens signed before a rotation stay verifiable until they expire
+pub struct Signer
+{
+    issuer: String,
+    access_ttl: Duration,
+
+    // (kid, key) sorted by kid, the last one is used for signing
+    keys: Vec<(String, SigningKey)>,
+}
+
+impl Signer
+{
+    // read every *.pem (PKCS#8 Ed25519 private key) in the directory, the file name without the extension is the key id,
+    // without a directory (only allowed with memory storage) a throwaway key is generated and tokens do not survive a restart
+    pub fn new(keys_dir: Option<&Path>, issuer: &str, access_ttl: Duration) -> Result<Self, String>
+    {
+        let keys = match keys_dir
+        {
+            Some(dir) => load_keys(dir)?,
+            None =>
+            {
+                log::warn!("No jwt.keys_dir configured, signing tokens with a temporary key");
+                vec![(Uuid::new_v4().to_string(), SigningKey::generate(&mut OsRng))]
+            }
+        };
+
+        Ok(Signer { issuer: issuer.to_string(), access_ttl, keys })
+    }
+
+    pub fn access_ttl(&self) -> Duration
+    {
+        self.access_ttl
+    }
+
+    // the permissions are those of the user's roles at the time the token is issued
+    pub fn access_token(&self, user: &User, permissions: Vec<String>) -> String
+    {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        let claims = Claims
+        {
+            iss: self.issuer.clone(),
+            sub: user.id.unwrap_or_default(),
+            roles: user.roles.clone().unwrap_or_default(),
+            permissions,
+            banned: user.banned.unwrap_or_default(),
+            iat: now,
+            exp: now + self.access_ttl.as_secs(),
+        };
+
+        let (kid, key) = self.keys.last().expect("there is always a signing key");
+
+        let header = json!({ "alg": "EdDSA", "typ": "JWT", "kid": kid });
+        let signed = format!("{}.{}", encode(&header), encode(&claims));
+        let signature = key.sign(signed.as_bytes());
+
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature.to_bytes()))
+    }
+
+    // check the signature, issuer and expiry of an access token, None if any of them is wrong
+    pub fn verify(&self, token: &str) -> Option<Claims>
+    {
+        let (signed, signature) = token.rsplit_once('.')?;
+        let (header, claims) = signed.split_once('.')?;
+
+        let header: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+
+        // only the algorithm we sign with is accepted, whatever the header claims
+        if header["alg"] != "EdDSA"
+        {
+            return None;
+        }
+
+        let (_, key) = self.keys.iter().find(|(kid, _)| header["kid"] == kid.as_str())?;
+        let signature = Signature::from_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?).ok()?;
+
+        VerifyingKey::from(key).verify_strict(signed.as_bytes(), &signature).ok()?;
+
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        match claims.iss == self.issuer && claims.exp > now
+        {
+            true => Some(claims),
+            false => None,
+        }
+    }
+
+    // the public half of every key as a JSON Web Key Set (RFC 7517, RFC 8037)
+    pub fn jwks(&self) -> serde_json::Value
+    {
+        let keys: Vec<serde_json::Value> = self.keys.iter().rev().map(|(kid, key)| json!(
+        {
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "use": "sig",
+            "alg": "EdDSA",
+            "kid": kid,
+            "x": URL_SAFE_NO_PAD.encode(VerifyingKey::from(key).as_bytes()),
+        })).collect();
+
+        json!({ "keys": keys })
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> String
+{
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap())
+}
+
+fn load_keys(dir: &Path) -> Result<Vec<(String, SigningKey)>, String>
+{
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("cannot read {}: {}", dir.display(), e))?;
+
+    let mut keys = Vec::new();
+
+    for entry in entries
+    {
+        let path = entry.map_err(|e| format!("cannot read {}: {}", dir.display(), e))?.path();
+
+        if path.extension().is_none_or(|extension| extension != "pem")
+        {
+            continue;
+        }
+
+        let kid = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        let pem = std::fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let key = SigningKey::from_pkcs8_pem(&pem).map_err(|e| format!("{} is not an Ed25519 private key: {}", path.display(), e))?;
+
+        keys.push((kid, key));
+    }
+
+    if keys.is_empty()
+    {
+        return Err(format!("no *.pem keys in {}", dir.display()));
+    }
+
+    // name the files so the newest sorts last, e.g. by date
+    keys.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(keys)
+}
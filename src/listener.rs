@@ -0,0 +1,153 @@
+use std::future::poll_fn;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio_postgres::{AsyncMessage, Client, Config, NoTls, Notification};
+use uuid::Uuid;
+
+use crate::feed::Feed;
+use crate::outbox::{Kind, OutboxEvent};
+use crate::repository::UserRepository;
+
+// the channel the outbox_notify and users_notify triggers announce events on
+const CHANNEL: &str = "user_events";
+
+// the wait before connecting again doubles after every failure, up to MAX_RECONNECT_DELAY
+const FIRST_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// a listener only ever waits, keepalives are how it notices the database went away without saying so
+const KEEPALIVE_IDLE: Duration = Duration::from_secs(30);
+
+// keep a connection of its own listening for the events every replica commits and pass them to the feed,
+// listening again whenever the connection is lost
+pub fn spawn(database_url: String, feed: Feed, users: Arc<dyn UserRepository>)
+{
+    tokio::spawn(async move
+    {
+        let mut delay = FIRST_RECONNECT_DELAY;
+        let mut connected_before = false;
+
+        loop
+        {
+            let result = listen(&database_url, ||
+            {
+                // nothing was heard while the connection was down
+                if connected_before
+                {
+                    feed.restart();
+                }
+
+                connected_before = true;
+                delay = FIRST_RECONNECT_DELAY;
+
+                log::info!("Listening for user events on '{}'", CHANNEL);
+            }, &feed, users.as_ref()).await;
+
+            match result
+            {
+                Ok(()) => log::warn!("The connection listening for user events was closed, connecting again in {:?}", delay),
+                Err(e) => log::warn!("Cannot listen for user events, trying again in {:?}: {}", delay, e),
+            }
+
+            tokio::time::sleep(delay).await;
+
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    });
+}
+
+// connect, LISTEN and publish what is heard until the connection fails
+async fn listen(database_url: &str, on_listening: impl FnOnce(), feed: &Feed, users: &dyn UserRepository) -> Result<(), tokio_postgres::Error>
+{
+    let mut config: Config = database_url.parse()?;
+    config.keepalives(true).keepalives_idle(KEEPALIVE_IDLE);
+
+    let (client, mut connection) = config.connect(NoTls).await?;
+
+    // notifications arrive through the connection, which only makes progress while it is polled
+    let (sender, mut notifications) = mpsc::unbounded_channel();
+
+    let driver = tokio::spawn(async move
+    {
+        while let Some(message) = poll_fn(|cx| connection.poll_message(cx)).await
+        {
+            if let AsyncMessage::Notification(notification) = message?
+            {
+                // the listener is gone
+                if sender.send(notification).is_err()
+                {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    });
+
+    client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+
+    on_listening();
+
+    while let Some(notification) = notifications.recv().await
+    {
+        match event(&client, users, &notification).await
+        {
+            Ok(Some(event)) => feed.publish(event),
+
+            // the event was relayed and removed before it could be read, the clients have to reload
+            Ok(None) =>
+            {
+                log::warn!("User event {} is gone from the outbox, restarting the feed", notification.payload());
+                feed.restart();
+            }
+            Err(e) =>
+            {
+                log::warn!("Cannot read user event {}: {}", notification.payload(), e);
+                feed.restart();
+            }
+        }
+    }
+
+    driver.await.unwrap_or(Ok(()))
+}
+
+// the event a notification announces, read back from the outbox when it was too large to be sent along
+async fn event(client: &Client, users: &dyn UserRepository, notification: &Notification) -> Result<Option<OutboxEvent>, Box<dyn std::error::Error>>
+{
+    let mut payload: serde_json::Value = serde_json::from_str(notification.payload())?;
+
+    if payload.get("data").is_some()
+    {
+        return Ok(Some(serde_json::from_value(payload)?));
+    }
+
+    // a change to the users table without an event of its own, the user is sent as they are now
+    if payload.get("type").is_some()
+    {
+        let user_id: Uuid = serde_json::from_value(payload["user_id"].clone())?;
+
+        // a user removed from the table is only known by their id
+        payload["data"] = match users.get_including_deleted(user_id).await.map_err(|e| format!("{:?}", e))?
+        {
+            Some(user) => serde_json::to_value(user)?,
+            None => serde_json::json!({ "id": user_id }),
+        };
+
+        return Ok(Some(serde_json::from_value(payload)?));
+    }
+
+    let id = payload.get("id").and_then(|id| id.as_i64()).ok_or("the notification names no event")?;
+
+    let row = client.query_opt("SELECT id, type, user_id, data, occurred_at FROM outbox WHERE id = $1", &[&id]).await?;
+
+    Ok(row.map(|row| OutboxEvent
+    {
+        id: row.get("id"),
+        kind: Kind::from_name(row.get("type")).expect("the table only accepts known types"),
+        user_id: row.get("user_id"),
+        data: row.get("data"),
+        occurred_at: row.get("occurred_at"),
+    }))
+}
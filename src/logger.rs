@@ -0,0 +1,30 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+// writes log records to stderr, prefixed with their level
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl Log for Logger
+{
+    fn enabled(&self, metadata: &Metadata) -> bool
+    {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record)
+    {
+        if self.enabled(record.metadata())
+        {
+            eprintln!("[{}] {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init(level: LevelFilter)
+{
+    log::set_logger(&LOGGER).expect("logger is only initialised once");
+    log::set_max_level(level);
+}
@@ -0,0 +1,219 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+use crate::config::{MailConfig, MailTransport};
+use crate::http::Url;
+
+// a server that has not taken the message after this long has failed
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// longest reply line read from the SMTP server
+const MAX_REPLY_SIZE: u64 = 8 * 1024;
+
+// a plain text message to one recipient, the subject has to be ASCII
+pub struct Mail
+{
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// how messages leave the service
+#[async_trait]
+pub trait Mailer: Send + Sync
+{
+    // Ok once the message is handed over, whether it arrives is up to the mail servers
+    async fn send(&self, mail: &Mail) -> std::io::Result<()>;
+}
+
+// hands every message to an SMTP server, with AUTH PLAIN when the URL has credentials;
+// there is no TLS, so the server should be a relay on the same network
+pub struct SmtpMailer
+{
+    url: Url,
+    from: String,
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer
+{
+    async fn send(&self, mail: &Mail) -> std::io::Result<()>
+    {
+        match tokio::time::timeout(SMTP_TIMEOUT, self.transfer(mail)).await
+        {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "the SMTP server did not answer in time")),
+        }
+    }
+}
+
+impl SmtpMailer
+{
+    async fn transfer(&self, mail: &Mail) -> std::io::Result<()>
+    {
+        let mut connection = BufReader::new(TcpStream::connect((self.url.host.as_str(), self.url.port)).await?);
+
+        // 220 service ready
+        smtp_reply(&mut connection, b'2').await?;
+
+        smtp_command(&mut connection, &format!("EHLO {}", domain(&self.from)), b'2').await?;
+
+        if let Some(password) = &self.url.password
+        {
+            let credentials = STANDARD.encode(format!("\0{}\0{}", self.url.username.as_deref().unwrap_or_default(), password));
+
+            smtp_command(&mut connection, &format!("AUTH PLAIN {}", credentials), b'2').await?;
+        }
+
+        smtp_command(&mut connection, &format!("MAIL FROM:<{}>", self.from), b'2').await?;
+        smtp_command(&mut connection, &format!("RCPT TO:<{}>", mail.to), b'2').await?;
+        smtp_command(&mut connection, "DATA", b'3').await?;
+
+        // a line that starts with a dot gets another one, a dot on its own ends the message
+        let mut data = String::new();
+
+        for line in message(&self.from, mail).lines()
+        {
+            if line.starts_with('.')
+            {
+                data.push('.');
+            }
+
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+
+        data.push_str(".\r\n");
+
+        connection.write_all(data.as_bytes()).await?;
+        connection.flush().await?;
+
+        smtp_reply(&mut connection, b'2').await?;
+
+        // the message is accepted already, how the server takes leave does not matter
+        let _ = smtp_command(&mut connection, "QUIT", b'2').await;
+
+        Ok(())
+    }
+}
+
+// appends every message to a file, or prints it when there is no path, so tokens can be read without a mail server
+pub struct FileMailer
+{
+    path: Option<PathBuf>,
+    from: String,
+}
+
+#[async_trait]
+impl Mailer for FileMailer
+{
+    async fn send(&self, mail: &Mail) -> std::io::Result<()>
+    {
+        let text = format!("{}\n", message(&self.from, mail));
+
+        match &self.path
+        {
+            Some(path) =>
+            {
+                let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+
+                file.write_all(text.as_bytes()).await
+            }
+            None =>
+            {
+                let mut stdout = tokio::io::stdout();
+
+                stdout.write_all(text.as_bytes()).await?;
+                stdout.flush().await
+            }
+        }
+    }
+}
+
+// send a command and wait for a reply of the expected class (2 for success, 3 for go ahead)
+async fn smtp_command(connection: &mut BufReader<TcpStream>, command: &str, class: u8) -> std::io::Result<()>
+{
+    connection.write_all(format!("{}\r\n", command).as_bytes()).await?;
+    connection.flush().await?;
+
+    smtp_reply(connection, class).await
+}
+
+// read a reply, which can span several lines ("250-...") up to the last one ("250 ...")
+async fn smtp_reply(connection: &mut BufReader<TcpStream>, class: u8) -> std::io::Result<()>
+{
+    loop
+    {
+        let mut line = String::new();
+
+        (&mut *connection).take(MAX_REPLY_SIZE).read_line(&mut line).await?;
+
+        if line.len() < 4 || !line.as_bytes()[..3].iter().all(u8::is_ascii_digit)
+        {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid reply from the SMTP server"));
+        }
+
+        if line.as_bytes()[3] == b'-'
+        {
+            continue;
+        }
+
+        // the command is left out of the error, it may be the credentials
+        return match line.as_bytes()[0] == class
+        {
+            true => Ok(()),
+            false => Err(std::io::Error::other(format!("the SMTP server answered {}", line.trim_end()))),
+        };
+    }
+}
+
+// the message as it is sent (RFC 5322), with \n line endings
+fn message(from: &str, mail: &Mail) -> String
+{
+    let date = OffsetDateTime::now_utc().format(&Rfc2822).expect("the current time fits RFC 2822");
+
+    format!(
+        "From: {}\nTo: {}\nSubject: {}\nDate: {}\nMessage-ID: <{}@{}>\nMIME-Version: 1.0\nContent-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: 8bit\n\n{}\n",
+        from, mail.to, mail.subject, date, Uuid::new_v4(), domain(from), mail.body.trim_end(),
+    )
+}
+
+fn domain(address: &str) -> &str
+{
+    address.rsplit_once('@').map_or("localhost", |(_, domain)| domain)
+}
+
+// send a message to a user without waiting for the server, a slow one does not hold up the request and a failure is only logged
+pub fn spawn_send(mailer: Arc<dyn Mailer>, mail: Mail, user_id: Uuid)
+{
+    tokio::spawn(async move
+    {
+        if let Err(e) = mailer.send(&mail).await
+        {
+            log::warn!("Cannot send \"{}\" to user {}: {}", mail.subject, user_id, e);
+        }
+    });
+}
+
+// the configured mailer
+pub fn mailer(config: &MailConfig) -> Arc<dyn Mailer>
+{
+    let from = config.from.clone();
+
+    match config.transport
+    {
+        MailTransport::Stdout => Arc::new(FileMailer { path: None, from }),
+        MailTransport::File => Arc::new(FileMailer { path: Some(PathBuf::from(&config.target)), from }),
+        MailTransport::Smtp => Arc::new(SmtpMailer { url: config.target.parse().expect("mail.target is validated"), from }),
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+use tokio_postgres::{Client, NoTls};
+
+// arbitrary key for pg_advisory_lock, shared by every replica of the service
+const LOCK_KEY: i64 = 0x7573_6572_735f_6d67;
+
+pub struct Migration
+{
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+// every migration compiled into the binary, in the order they are applied
+pub const MIGRATIONS: &[Migration] =
+&[
+    Migration { version: 1, name: "create_users", sql: include_str!("../migrations/0001_create_users.sql") },
+    Migration { version: 2, name: "add_created_at", sql: include_str!("../migrations/0002_add_created_at.sql") },
+    Migration { version: 3, name: "create_credentials", sql: include_str!("../migrations/0003_create_credentials.sql") },
+    Migration { version: 4, name: "create_refresh_tokens", sql: include_str!("../migrations/0004_create_refresh_tokens.sql") },
+    Migration { version: 5, name: "create_roles", sql: include_str!("../migrations/0005_create_roles.sql") },
+    Migration { version: 6, name: "create_bans", sql: include_str!("../migrations/0006_create_bans.sql") },
+    Migration { version: 7, name: "add_user_timestamps", sql: include_str!("../migrations/0007_add_user_timestamps.sql") },
+    Migration { version: 8, name: "add_deleted_at", sql: include_str!("../migrations/0008_add_deleted_at.sql") },
+    Migration { version: 9, name: "create_audit_log", sql: include_str!("../migrations/0009_create_audit_log.sql") },
+    Migration { version: 10, name: "create_outbox", sql: include_str!("../migrations/0010_create_outbox.sql") },
+    Migration { version: 11, name: "create_webhooks", sql: include_str!("../migrations/0011_create_webhooks.sql") },
+    Migration { version: 12, name: "notify_user_events", sql: include_str!("../migrations/0012_notify_user_events.sql") },
+    Migration { version: 13, name: "add_email_verification", sql: include_str!("../migrations/0013_add_email_verification.sql") },
+    Migration { version: 14, name: "create_password_resets", sql: include_str!("../migrations/0014_create_password_resets.sql") },
+    Migration { version: 15, name: "add_unbanned_events", sql: include_str!("../migrations/0015_add_unbanned_events.sql") },
+    Migration { version: 16, name: "add_outbox_retries", sql: include_str!("../migrations/0016_add_outbox_retries.sql") },
+];
+
+impl Migration
+{
+    pub fn checksum(&self) -> String
+    {
+        Sha256::digest(self.sql.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+// a row of schema_migrations
+pub struct Applied
+{
+    pub version: i64,
+    pub name: String,
+    pub checksum: String,
+    pub applied_at: String,
+}
+
+#[derive(Debug)]
+pub enum MigrationError
+{
+    Db(tokio_postgres::Error),
+
+    // the database has migrations this binary does not know about
+    DatabaseAhead { version: i64, latest: i64 },
+
+    // an applied migration was edited after the fact
+    ChecksumMismatch { version: i64, name: String },
+}
+
+impl fmt::Display for MigrationError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            MigrationError::Db(e) => write!(f, "DB error: {}", e),
+            MigrationError::DatabaseAhead { version, latest } => write!(f, "the database is at schema version {} but this binary only knows up to {}, refusing to touch it", version, latest),
+            MigrationError::ChecksumMismatch { version, name } => write!(f, "migration {:04}_{} was changed after it had been applied", version, name),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<tokio_postgres::Error> for MigrationError
+{
+    fn from(e: tokio_postgres::Error) -> Self
+    {
+        MigrationError::Db(e)
+    }
+}
+
+// apply every pending migration (or only list them on a dry run), returns the pending ones
+pub async fn run(db_url: &str, dry_run: bool) -> Result<Vec<&'static Migration>, MigrationError>
+{
+    let mut client = connect(db_url).await?;
+
+    // a dry run writes nothing, not even the table or the lock
+    if dry_run
+    {
+        return pending(&recorded(&client).await?);
+    }
+
+    // replicas starting at the same time wait here for the first one to finish,
+    // the lock goes away with the connection even if we bail out halfway
+    client.execute("SELECT pg_advisory_lock($1)", &[&LOCK_KEY]).await?;
+
+    create_table(&client).await?;
+
+    let pending = pending(&applied(&client).await?)?;
+
+    for migration in &pending
+    {
+        let transaction = client.transaction().await?;
+
+        transaction.batch_execute(migration.sql).await?;
+        transaction.execute("INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)", &[&migration.version, &migration.name, &migration.checksum()]).await?;
+        transaction.commit().await?;
+
+        log::info!("Applied migration {:04}_{}", migration.version, migration.name);
+    }
+
+    client.execute("SELECT pg_advisory_unlock($1)", &[&LOCK_KEY]).await?;
+
+    Ok(pending)
+}
+
+// applied migrations as recorded in the database, empty if nothing was ever applied
+pub async fn status(db_url: &str) -> Result<Vec<Applied>, MigrationError>
+{
+    let client = connect(db_url).await?;
+
+    recorded(&client).await
+}
+
+// compare the applied migrations with the ones compiled in
+pub fn pending(applied: &[Applied]) -> Result<Vec<&'static Migration>, MigrationError>
+{
+    let latest = MIGRATIONS.last().map(|m| m.version).unwrap_or_default();
+
+    for row in applied
+    {
+        match MIGRATIONS.iter().find(|m| m.version == row.version)
+        {
+            Some(migration) if migration.checksum() != row.checksum => return Err(MigrationError::ChecksumMismatch { version: row.version, name: row.name.clone() }),
+            Some(_) => {}
+            None => return Err(MigrationError::DatabaseAhead { version: row.version, latest }),
+        }
+    }
+
+    Ok(MIGRATIONS.iter().filter(|m| !applied.iter().any(|row| row.version == m.version)).collect())
+}
+
+async fn connect(db_url: &str) -> Result<Client, MigrationError>
+{
+    let (client, connection) = tokio_postgres::connect(db_url, NoTls).await?;
+
+    tokio::spawn(async move
+    {
+        if let Err(e) = connection.await
+        {
+            log::error!("Migration connection error: {}", e);
+        }
+    });
+
+    Ok(client)
+}
+
+async fn create_table(client: &Client) -> Result<(), MigrationError>
+{
+    client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations
+        (
+            version BIGINT PRIMARY KEY,
+            name TEXT NOT NULL,
+            checksum TEXT NOT NULL,
+            applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );"
+    ).await?;
+
+    Ok(())
+}
+
+// like applied, but a database without the table has nothing applied yet instead of failing
+async fn recorded(client: &Client) -> Result<Vec<Applied>, MigrationError>
+{
+    let exists: bool = client.query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[]).await?.get(0);
+
+    match exists
+    {
+        true => applied(client).await,
+        false => Ok(Vec::new()),
+    }
+}
+
+async fn applied(client: &Client) -> Result<Vec<Applied>, MigrationError>
+{
+    let rows = client.query("SELECT version, name, checksum, applied_at::text FROM schema_migrations ORDER BY version", &[]).await?;
+
+    Ok(rows.iter().map(|row| Applied { version: row.get(0), name: row.get(1), checksum: row.get(2), applied_at: row.get(3) }).collect())
+}
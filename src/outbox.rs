@@ -0,0 +1,317 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+use crate::config::{OutboxConfig, OutboxSink};
+use crate::http::{self, Url};
+use crate::repository::OutboxRepository;
+use crate::timestamp;
+
+// events handed to the sink per round trip to the store, the relay goes on right away while batches come back full
+const BATCH_SIZE: usize = 100;
+
+// a sink that has not taken an event after this long has failed
+const SINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how long claimed events are left to the replica that claimed them, enough for the sink to time out on a whole batch,
+// another one sends them again afterwards
+const LEASE: Duration = Duration::from_secs(SINK_TIMEOUT.as_secs() * BATCH_SIZE as u64);
+
+// the delay doubles after every attempt the sink refuses, from FIRST_RETRY up to MAX_RETRY_DELAY, events are never given up
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+// longest reply line read from Redis
+const MAX_REPLY_SIZE: u64 = 8 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Kind
+{
+    // data is the new user
+    #[serde(rename = "UserCreated")]
+    Created,
+
+    // data is the user after the change: an edit, a restore, a role given or taken away or an email verified
+    #[serde(rename = "UserUpdated")]
+    Updated,
+
+    // data is the ban
+    #[serde(rename = "UserBanned")]
+    Banned,
+
+    // data is the ban with its lifted_at, whether a moderator lifted it or it expired
+    #[serde(rename = "UserUnbanned")]
+    Unbanned,
+
+    // data is the user with their deleted_at, purging them later does not send another event
+    #[serde(rename = "UserDeleted")]
+    Deleted,
+}
+
+impl Kind
+{
+    const ALL: [Kind; 5] = [Kind::Created, Kind::Updated, Kind::Banned, Kind::Unbanned, Kind::Deleted];
+
+    pub fn name(self) -> &'static str
+    {
+        match self
+        {
+            Kind::Created => "UserCreated",
+            Kind::Updated => "UserUpdated",
+            Kind::Banned => "UserBanned",
+            Kind::Unbanned => "UserUnbanned",
+            Kind::Deleted => "UserDeleted",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Kind>
+    {
+        Kind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    // what webhooks subscribe to
+    pub fn topic(self) -> &'static str
+    {
+        match self
+        {
+            Kind::Created => "created",
+            Kind::Updated => "updated",
+            Kind::Banned => "banned",
+            Kind::Unbanned => "unbanned",
+            Kind::Deleted => "deleted",
+        }
+    }
+
+    pub fn from_topic(topic: &str) -> Option<Kind>
+    {
+        Kind::ALL.into_iter().find(|kind| kind.topic() == topic)
+    }
+}
+
+// an event as it waits in the outbox and reaches the sink, it may reach it more than once
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OutboxEvent
+{
+    // grows with every event, so the same id means the same event and for one user a higher id is a later change
+    pub id: i64,
+
+    #[serde(rename = "type")]
+    pub kind: Kind,
+
+    pub user_id: Uuid,
+    pub data: serde_json::Value,
+
+    #[serde(with = "timestamp")]
+    pub occurred_at: SystemTime,
+}
+
+// where the relay delivers events to
+#[async_trait]
+pub trait Sink: Send + Sync
+{
+    // only answer Ok once the event cannot get lost anymore, the outbox forgets it afterwards
+    async fn send(&self, event: &OutboxEvent) -> std::io::Result<()>;
+}
+
+// POSTs every event as JSON to one URL, any 2xx counts as delivered
+pub struct WebhookSink
+{
+    url: Url,
+}
+
+#[async_trait]
+impl Sink for WebhookSink
+{
+    async fn send(&self, event: &OutboxEvent) -> std::io::Result<()>
+    {
+        let body = serde_json::to_vec(event).unwrap();
+        let headers = [("Content-Type", "application/json".to_string()), ("X-Event-Id", event.id.to_string())];
+
+        match http::post(&self.url, &headers, &body).await?
+        {
+            200..=299 => Ok(()),
+            status => Err(std::io::Error::other(format!("the webhook answered {}", status))),
+        }
+    }
+}
+
+// appends every event as a line of JSON (NDJSON), synced to disk before it counts as delivered
+pub struct FileSink
+{
+    path: PathBuf,
+}
+
+#[async_trait]
+impl Sink for FileSink
+{
+    async fn send(&self, event: &OutboxEvent) -> std::io::Result<()>
+    {
+        let mut line = serde_json::to_vec(event).unwrap();
+        line.push(b'\n');
+
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await?;
+
+        file.write_all(&line).await?;
+        file.sync_data().await
+    }
+}
+
+// adds every event to a Redis stream with XADD, the fields are id, type, user_id and event (the whole event as JSON)
+pub struct RedisSink
+{
+    url: Url,
+    stream: String,
+}
+
+#[async_trait]
+impl Sink for RedisSink
+{
+    async fn send(&self, event: &OutboxEvent) -> std::io::Result<()>
+    {
+        let mut connection = BufReader::new(TcpStream::connect((self.url.host.as_str(), self.url.port)).await?);
+
+        if let Some(password) = &self.url.password
+        {
+            redis_command(&mut connection, &["AUTH", password]).await?;
+        }
+
+        let (id, user_id, json) = (event.id.to_string(), event.user_id.to_string(), serde_json::to_string(event).unwrap());
+
+        redis_command(&mut connection, &["XADD", &self.stream, "*", "id", &id, "type", event.kind.name(), "user_id", &user_id, "event", &json]).await
+    }
+}
+
+// send a command and wait for a reply that is not an error, the reply itself is not needed
+async fn redis_command(connection: &mut BufReader<TcpStream>, args: &[&str]) -> std::io::Result<()>
+{
+    let mut command = format!("*{}\r\n", args.len());
+
+    for arg in args
+    {
+        command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+
+    connection.write_all(command.as_bytes()).await?;
+    connection.flush().await?;
+
+    let mut reply = String::new();
+
+    (&mut *connection).take(MAX_REPLY_SIZE).read_line(&mut reply).await?;
+
+    match reply.chars().next()
+    {
+        Some('+') | Some('$') => Ok(()),
+        Some('-') => Err(std::io::Error::other(format!("Redis refused {}: {}", args[0], reply[1..].trim_end()))),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid reply from Redis")),
+    }
+}
+
+// the configured sink, None when events are left in the outbox for another replica to relay
+pub fn sink(config: &OutboxConfig) -> Option<Box<dyn Sink>>
+{
+    let url = || config.target.parse::<Url>().expect("outbox.target is validated");
+
+    match config.sink
+    {
+        OutboxSink::None => None,
+        OutboxSink::Webhook => Some(Box::new(WebhookSink { url: url() })),
+        OutboxSink::File => Some(Box::new(FileSink { path: PathBuf::from(&config.target) })),
+        OutboxSink::Redis =>
+        {
+            let url = url();
+            let stream = url.path.trim_start_matches('/').to_string();
+
+            Some(Box::new(RedisSink { url, stream }))
+        }
+    }
+}
+
+// periodically deliver what is in the outbox, oldest first, an event the sink refuses holds up the later ones of its user
+pub fn spawn_relay_task(outbox: Arc<dyn OutboxRepository>, sink: Box<dyn Sink>, interval: Duration)
+{
+    tokio::spawn(async move
+    {
+        let sink = TimedSink(sink);
+        let mut ticker = tokio::time::interval(interval);
+
+        loop
+        {
+            ticker.tick().await;
+
+            while relay(outbox.as_ref(), &sink).await == BATCH_SIZE {}
+        }
+    });
+}
+
+// claim a batch of events and hand them to the sink, the number claimed
+async fn relay(outbox: &dyn OutboxRepository, sink: &dyn Sink) -> usize
+{
+    let claimed = match outbox.claim_events(BATCH_SIZE, LEASE).await
+    {
+        Ok(claimed) => claimed,
+        Err(e) =>
+        {
+            log::warn!("Cannot claim events: {:?}", e);
+            return 0;
+        }
+    };
+
+    // users with a refused event in this batch, their later events go out after it
+    let mut held = Vec::new();
+
+    for (event, attempts) in &claimed
+    {
+        if held.contains(&event.user_id)
+        {
+            continue;
+        }
+
+        let recorded = match sink.send(event).await
+        {
+            Ok(()) => outbox.delivered(event.id).await,
+            Err(e) =>
+            {
+                log::warn!("Cannot deliver event {}: {}", event.id, e);
+                held.push(event.user_id);
+
+                outbox.failed(event.id, &e.to_string(), SystemTime::now() + backoff(attempts + 1)).await
+            }
+        };
+
+        // the lease runs out and the event is sent again
+        if let Err(e) = recorded
+        {
+            log::warn!("Cannot record the delivery of event {}: {:?}", event.id, e);
+        }
+    }
+
+    claimed.len()
+}
+
+// the delay before the next attempt after the given number of refused ones
+fn backoff(attempts: i32) -> Duration
+{
+    FIRST_RETRY.saturating_mul(1 << (attempts - 1).clamp(0, 16)).min(MAX_RETRY_DELAY)
+}
+
+// keeps a sink that hangs from holding up the relay forever
+struct TimedSink(Box<dyn Sink>);
+
+#[async_trait]
+impl Sink for TimedSink
+{
+    async fn send(&self, event: &OutboxEvent) -> std::io::Result<()>
+    {
+        match tokio::time::timeout(SINK_TIMEOUT, self.0.send(event)).await
+        {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "the sink did not answer in time")),
+        }
+    }
+}
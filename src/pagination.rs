@@ -0,0 +1,476 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::audit::Action;
+use crate::error::ApiError;
+use crate::router::{percent_encode, Query};
+use crate::timestamp;
+use crate::webhooks::DeliveryStatus;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+
+// larger limits are clamped to this
+pub const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField
+{
+    Name,
+    Email,
+    Created,
+}
+
+impl SortField
+{
+    pub fn column(self) -> &'static str
+    {
+        match self
+        {
+            SortField::Name => "name",
+            SortField::Email => "email",
+            SortField::Created => "created_at",
+        }
+    }
+
+    fn param(self) -> &'static str
+    {
+        match self
+        {
+            SortField::Name => "name",
+            SortField::Email => "email",
+            SortField::Created => "created",
+        }
+    }
+}
+
+// value of the sort column for one user
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SortKey
+{
+    Text(String),
+    Time(SystemTime),
+}
+
+// position of the last row of a page, handed out to clients as an opaque string
+#[derive(Serialize, Deserialize)]
+pub struct Cursor
+{
+    sort: SortField,
+    descending: bool,
+    pub id: Uuid,
+
+    // value of the sort column, microseconds since the epoch for timestamps
+    key: serde_json::Value,
+}
+
+impl Cursor
+{
+    pub fn key(&self) -> Result<SortKey, ApiError>
+    {
+        let corrupted = || ApiError::InvalidQuery("the cursor is corrupted".to_string());
+
+        match self.sort
+        {
+            SortField::Created => Ok(SortKey::Time(UNIX_EPOCH + Duration::from_micros(self.key.as_u64().ok_or_else(corrupted)?))),
+            _ => Ok(SortKey::Text(self.key.as_str().ok_or_else(corrupted)?.to_string())),
+        }
+    }
+
+    fn encode(&self) -> String
+    {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    fn decode(value: &str) -> Option<Cursor>
+    {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(value).ok()?).ok()
+    }
+}
+
+// a GET /users request: filters, sort order and the page to return
+pub struct ListQuery
+{
+    pub limit: i64,
+    pub sort: SortField,
+    pub descending: bool,
+    pub after: Option<Cursor>,
+
+    pub role: Option<String>,
+    pub banned: Option<bool>,
+    pub email_domain: Option<String>,
+
+    // both exclusive
+    pub created_after: Option<SystemTime>,
+    pub created_before: Option<SystemTime>,
+
+    // also list users that are deleted but not purged yet
+    pub include_deleted: bool,
+}
+
+impl ListQuery
+{
+    pub fn from_query(query: &Query) -> Result<Self, ApiError>
+    {
+        let limit = query.get::<i64>("limit").map_err(invalid("limit", "a positive number"))?.unwrap_or(DEFAULT_PAGE_SIZE);
+
+        if limit < 1
+        {
+            return Err(ApiError::InvalidQuery("limit must be at least 1".to_string()));
+        }
+
+        // "-name" sorts by name in descending order
+        let sort = query.get::<String>("sort").unwrap_or_default().unwrap_or_else(|| "name".to_string());
+        let (descending, field) = match sort.strip_prefix('-')
+        {
+            Some(field) => (true, field),
+            None => (false, sort.as_str()),
+        };
+
+        let sort = match field
+        {
+            "name" => SortField::Name,
+            "email" => SortField::Email,
+            "created" => SortField::Created,
+
+            _ => return Err(invalid("sort", "name, email or created, optionally prefixed with '-'")(sort)),
+        };
+
+        let after = match query.get::<String>("after").unwrap_or_default()
+        {
+            Some(after) => match Cursor::decode(&after)
+            {
+                Some(cursor) if cursor.sort == sort && cursor.descending == descending => Some(cursor),
+                Some(_) => return Err(ApiError::InvalidQuery("the cursor belongs to a different sort order".to_string())),
+                None => return Err(invalid("after", "a cursor from a previous page")(after)),
+            },
+            None => None,
+        };
+
+        Ok(ListQuery
+        {
+            limit: limit.min(MAX_PAGE_SIZE),
+            sort,
+            descending,
+            after,
+
+            role: query.get("role").unwrap_or_default(),
+            banned: query.get("banned").map_err(invalid("banned", "true or false"))?,
+            email_domain: query.get("email_domain").unwrap_or_default(),
+            created_after: time_param(query, "created_after")?,
+            created_before: time_param(query, "created_before")?,
+            include_deleted: query.get("include_deleted").map_err(invalid("include_deleted", "true or false"))?.unwrap_or(false),
+        })
+    }
+
+    // link to the page that starts after the given user, keeping the filters and sort order
+    pub fn next_link(&self, id: Uuid, key: SortKey) -> String
+    {
+        let key = match key
+        {
+            SortKey::Time(time) => serde_json::json!(time.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64),
+            SortKey::Text(text) => serde_json::json!(text),
+        };
+
+        let cursor = Cursor { sort: self.sort, descending: self.descending, id, key };
+
+        let sort = format!("{}{}", if self.descending { "-" } else { "" }, self.sort.param());
+
+        let mut params = vec![("limit", self.limit.to_string()), ("sort", sort)];
+
+        if let Some(role) = &self.role
+        {
+            params.push(("role", role.clone()));
+        }
+
+        if let Some(banned) = self.banned
+        {
+            params.push(("banned", banned.to_string()));
+        }
+
+        if let Some(domain) = &self.email_domain
+        {
+            params.push(("email_domain", domain.clone()));
+        }
+
+        if let Some(time) = self.created_after
+        {
+            params.push(("created_after", timestamp::format(time)));
+        }
+
+        if let Some(time) = self.created_before
+        {
+            params.push(("created_before", timestamp::format(time)));
+        }
+
+        if self.include_deleted
+        {
+            params.push(("include_deleted", "true".to_string()));
+        }
+
+        params.push(("after", cursor.encode()));
+
+        let query: Vec<String> = params.iter().map(|(key, value)| format!("{}={}", key, percent_encode(value))).collect();
+
+        format!("/users?{}", query.join("&"))
+    }
+}
+
+// a GET /audit or GET /users/{id}/audit request, entries come newest first
+pub struct AuditQuery
+{
+    pub limit: i64,
+
+    // id of the last entry of the previous page
+    pub after: Option<i64>,
+
+    pub user_id: Option<Uuid>,
+    pub actor_id: Option<Uuid>,
+    pub action: Option<Action>,
+    pub request_id: Option<String>,
+
+    // both exclusive
+    pub created_after: Option<SystemTime>,
+    pub created_before: Option<SystemTime>,
+
+    // the endpoint the query was sent to, for the next link
+    path: String,
+
+    // the user comes from the path rather than the query string
+    scoped: bool,
+}
+
+impl AuditQuery
+{
+    // user_id is set for the audit log of one user
+    pub fn from_query(query: &Query, path: &str, user_id: Option<Uuid>) -> Result<Self, ApiError>
+    {
+        let limit = query.get::<i64>("limit").map_err(invalid("limit", "a positive number"))?.unwrap_or(DEFAULT_PAGE_SIZE);
+
+        if limit < 1
+        {
+            return Err(ApiError::InvalidQuery("limit must be at least 1".to_string()));
+        }
+
+        let action = match query.get::<String>("action").unwrap_or_default()
+        {
+            Some(name) => Some(Action::from_name(&name).ok_or_else(|| invalid("action", "an audit action such as updated or banned")(name))?),
+            None => None,
+        };
+
+        let scoped = user_id.is_some();
+        let user_id = match scoped
+        {
+            true => user_id,
+            false => query.get("user_id").map_err(invalid("user_id", "a UUID"))?,
+        };
+
+        Ok(AuditQuery
+        {
+            limit: limit.min(MAX_PAGE_SIZE),
+            after: query.get("after").map_err(invalid("after", "an entry id from a previous page"))?,
+
+            user_id,
+            actor_id: query.get("actor_id").map_err(invalid("actor_id", "a UUID"))?,
+            action,
+            request_id: query.get("request_id").unwrap_or_default(),
+            created_after: time_param(query, "created_after")?,
+            created_before: time_param(query, "created_before")?,
+
+            path: path.to_string(),
+            scoped,
+        })
+    }
+
+    // link to the page that starts after the given entry, keeping the filters
+    pub fn next_link(&self, id: i64) -> String
+    {
+        let mut params = vec![("limit", self.limit.to_string())];
+
+        if let Some(user_id) = self.user_id.filter(|_| !self.scoped)
+        {
+            params.push(("user_id", user_id.to_string()));
+        }
+
+        if let Some(actor_id) = self.actor_id
+        {
+            params.push(("actor_id", actor_id.to_string()));
+        }
+
+        if let Some(action) = self.action
+        {
+            params.push(("action", action.name().to_string()));
+        }
+
+        if let Some(request_id) = &self.request_id
+        {
+            params.push(("request_id", request_id.clone()));
+        }
+
+        if let Some(time) = self.created_after
+        {
+            params.push(("created_after", timestamp::format(time)));
+        }
+
+        if let Some(time) = self.created_before
+        {
+            params.push(("created_before", timestamp::format(time)));
+        }
+
+        params.push(("after", id.to_string()));
+
+        let query: Vec<String> = params.iter().map(|(key, value)| format!("{}={}", key, percent_encode(value))).collect();
+
+        format!("{}?{}", self.path, query.join("&"))
+    }
+}
+
+// a GET /webhooks/{id}/deliveries request, deliveries come newest first
+pub struct DeliveryQuery
+{
+    pub limit: i64,
+
+    // id of the last delivery of the previous page
+    pub after: Option<i64>,
+
+    pub status: Option<DeliveryStatus>,
+
+    // the endpoint the query was sent to, for the next link
+    path: String,
+}
+
+impl DeliveryQuery
+{
+    pub fn from_query(query: &Query, path: &str) -> Result<Self, ApiError>
+    {
+        let limit = query.get::<i64>("limit").map_err(invalid("limit", "a positive number"))?.unwrap_or(DEFAULT_PAGE_SIZE);
+
+        if limit < 1
+        {
+            return Err(ApiError::InvalidQuery("limit must be at least 1".to_string()));
+        }
+
+        let status = match query.get::<String>("status").unwrap_or_default()
+        {
+            Some(name) => Some(DeliveryStatus::from_name(&name).ok_or_else(|| invalid("status", "pending, delivered or dead")(name))?),
+            None => None,
+        };
+
+        Ok(DeliveryQuery
+        {
+            limit: limit.min(MAX_PAGE_SIZE),
+            after: query.get("after").map_err(invalid("after", "a delivery id from a previous page"))?,
+            status,
+            path: path.to_string(),
+        })
+    }
+
+    // link to the page that starts after the given delivery, keeping the filter
+    pub fn next_link(&self, id: i64) -> String
+    {
+        let mut params = vec![("limit", self.limit.to_string())];
+
+        if let Some(status) = self.status
+        {
+            params.push(("status", status.name().to_string()));
+        }
+
+        params.push(("after", id.to_string()));
+
+        let query: Vec<String> = params.iter().map(|(key, value)| format!("{}={}", key, percent_encode(value))).collect();
+
+        format!("{}?{}", self.path, query.join("&"))
+    }
+}
+
+fn time_param(query: &Query, name: &'static str) -> Result<Option<SystemTime>, ApiError>
+{
+    match query.get::<String>(name).unwrap_or_default()
+    {
+        Some(value) => timestamp::parse(&value).map(Some).ok_or_else(|| invalid(name, "an RFC 3339 timestamp such as 2026-10-15T08:30:00Z")(value)),
+        None => Ok(None),
+    }
+}
+
+fn invalid(name: &'static str, expected: &'static str) -> impl Fn(String) -> ApiError
+{
+    move |value| ApiError::InvalidQuery(format!("'{}' is not a valid {}, expected {}", value, name, expected))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn list(query: &str) -> Result<ListQuery, ApiError>
+    {
+        ListQuery::from_query(&Query::parse(Some(query)).unwrap())
+    }
+
+    fn status(query: &str) -> Option<u16>
+    {
+        list(query).err().map(|e| e.status())
+    }
+
+    fn cursor(sort: SortField, descending: bool, key: serde_json::Value) -> String
+    {
+        Cursor { sort, descending, id: Uuid::new_v4(), key }.encode()
+    }
+
+    #[test]
+    fn a_cursor_survives_the_next_link()
+    {
+        let first = list("sort=-created&limit=10").unwrap();
+        let time = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let id = Uuid::new_v4();
+
+        let next = list(first.next_link(id, SortKey::Time(time)).split_once('?').unwrap().1).unwrap();
+        let after = next.after.unwrap();
+
+        assert_eq!(after.id, id);
+        assert!(after.key().unwrap() == SortKey::Time(time));
+        assert!(next.descending && next.sort == SortField::Created && next.limit == 10);
+    }
+
+    #[test]
+    fn garbage_after_is_a_bad_request()
+    {
+        assert_eq!(status("after=not-a-cursor"), Some(400));
+        assert_eq!(status("after=%7B%7D"), Some(400));
+
+        // cut short, the JSON inside no longer parses
+        let valid = cursor(SortField::Name, false, serde_json::json!("ada"));
+        assert_eq!(status(&format!("after={}", &valid[..valid.len() - 4])), Some(400));
+    }
+
+    #[test]
+    fn a_tampered_key_is_a_bad_request()
+    {
+        let after = list(&format!("sort=created&after={}", cursor(SortField::Created, false, serde_json::json!("yesterday")))).unwrap().after.unwrap();
+
+        assert_eq!(after.key().err().map(|e| e.status()), Some(400));
+    }
+
+    #[test]
+    fn a_cursor_only_fits_its_own_sort_order()
+    {
+        let by_name = cursor(SortField::Name, false, serde_json::json!("ada"));
+
+        assert!(list(&format!("sort=name&after={}", by_name)).is_ok());
+        assert_eq!(status(&format!("sort=email&after={}", by_name)), Some(400));
+        assert_eq!(status(&format!("sort=-name&after={}", by_name)), Some(400));
+    }
+
+    #[test]
+    fn the_limit_is_clamped()
+    {
+        assert_eq!(list("").unwrap().limit, DEFAULT_PAGE_SIZE);
+        assert_eq!(list("limit=1000").unwrap().limit, MAX_PAGE_SIZE);
+        assert_eq!(status("limit=0"), Some(400));
+        assert_eq!(status("limit=many"), Some(400));
+    }
+}
@@ -0,0 +1,160 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+use crate::error::{ApiError, FieldError};
+
+pub const MIN_LENGTH: usize = 8;
+
+// hashing megabytes of input would only be a way to burn our CPU, counted in characters like MIN_LENGTH
+pub const MAX_LENGTH: usize = 1024;
+
+pub enum Verified
+{
+    Invalid,
+    Valid,
+
+    // the password matched but the hash was made with other parameters and should be replaced
+    NeedsRehash,
+}
+
+// hashes and verifies passwords with Argon2id, the work is done on the blocking thread pool
+#[derive(Clone)]
+pub struct Hasher
+{
+    params: Params,
+
+    // verified against when there is no hash to check, so unknown users take as long as known ones
+    dummy: String,
+}
+
+impl Hasher
+{
+    // memory cost in KiB, time cost in iterations
+    pub fn new(memory_cost: u32, time_cost: u32, parallelism: u32) -> Result<Self, String>
+    {
+        let params = Params::new(memory_cost, time_cost, parallelism, None).map_err(|e| e.to_string())?;
+
+        let mut hasher = Hasher { params, dummy: String::new() };
+
+        hasher.dummy = hasher.hash_blocking("dummy password").map_err(|_| "cannot hash with these parameters".to_string())?;
+
+        Ok(hasher)
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String, ApiError>
+    {
+        let hasher = self.clone();
+        let password = password.to_string();
+
+        tokio::task::spawn_blocking(move || hasher.hash_blocking(&password)).await.map_err(|_| ApiError::Internal)?
+    }
+
+    // a missing hash always fails, after the same amount of work as a wrong password
+    pub async fn verify(&self, password: &str, hash: Option<String>) -> Result<Verified, ApiError>
+    {
+        let hasher = self.clone();
+        let password = password.to_string();
+
+        tokio::task::spawn_blocking(move || match hash
+        {
+            Some(hash) => hasher.verify_blocking(&password, &hash),
+            None =>
+            {
+                hasher.verify_blocking(&password, &hasher.dummy);
+                Verified::Invalid
+            }
+        }).await.map_err(|_| ApiError::Internal)
+    }
+
+    fn argon2(&self) -> Argon2<'static>
+    {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    fn hash_blocking(&self, password: &str) -> Result<String, ApiError>
+    {
+        let salt = SaltString::generate(&mut OsRng);
+
+        match self.argon2().hash_password(password.as_bytes(), &salt)
+        {
+            Ok(hash) => Ok(hash.to_string()),
+            Err(e) =>
+            {
+                log::error!("Cannot hash a password: {}", e);
+                Err(ApiError::Internal)
+            }
+        }
+    }
+
+    fn verify_blocking(&self, password: &str, hash: &str) -> Verified
+    {
+        let parsed = match PasswordHash::new(hash)
+        {
+            Ok(parsed) => parsed,
+            Err(e) =>
+            {
+                log::error!("Stored password hash is unreadable: {}", e);
+                return Verified::Invalid;
+            }
+        };
+
+        // the parameters come from the stored hash, the final comparison is constant-time
+        if self.argon2().verify_password(password.as_bytes(), &parsed).is_err()
+        {
+            return Verified::Invalid;
+        }
+
+        let current = match Params::try_from(&parsed)
+        {
+            Ok(params) => parsed.algorithm == Algorithm::Argon2id.ident() && parsed.version == Some(Version::V0x13.into()) && params.m_cost() == self.params.m_cost() && params.t_cost() == self.params.t_cost() && params.p_cost() == self.params.p_cost(),
+            Err(_) => false,
+        };
+
+        match current
+        {
+            true => Verified::Valid,
+            false => Verified::NeedsRehash,
+        }
+    }
+}
+
+// longer than any password that can be set, such input is refused before it is hashed
+pub fn too_long(password: &str) -> bool
+{
+    password.chars().count() > MAX_LENGTH
+}
+
+pub fn validate(field: &str, password: &str) -> Option<FieldError>
+{
+    let length = password.chars().count();
+
+    match length
+    {
+        _ if length < MIN_LENGTH => Some(FieldError::new(field, "too_short", &format!("Must be at least {} characters long.", MIN_LENGTH))),
+        _ if too_long(password) => Some(FieldError::new(field, "too_long", &format!("Must be at most {} characters long.", MAX_LENGTH))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // whatever can be set must not be refused as too long afterwards
+    #[test]
+    fn length_is_counted_in_characters()
+    {
+        let longest = "ж".repeat(MAX_LENGTH);
+
+        assert!(longest.len() > MAX_LENGTH);
+        assert!(validate("password", &longest).is_none());
+        assert!(!too_long(&longest));
+
+        let longer = "ж".repeat(MAX_LENGTH + 1);
+
+        assert!(validate("password", &longer).is_some());
+        assert!(too_long(&longer));
+    }
+}
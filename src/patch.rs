@@ -0,0 +1,302 @@
+use serde_json::{Map, Value};
+
+pub enum PatchError
+{
+    // the patch document itself is unusable
+    Invalid(String),
+
+    // a "test" operation did not match the current document
+    TestFailed(String),
+}
+
+// apply a JSON Merge Patch (RFC 7396)
+pub fn merge_patch(target: &mut Value, patch: &Value)
+{
+    let patch = match patch
+    {
+        Value::Object(patch) => patch,
+        _ =>
+        {
+            *target = patch.clone();
+            return;
+        }
+    };
+
+    if !target.is_object()
+    {
+        *target = Value::Object(Map::new());
+    }
+
+    let target = target.as_object_mut().unwrap();
+
+    for (key, value) in patch
+    {
+        match value
+        {
+            Value::Null => { target.remove(key); }
+            _ => merge_patch(target.entry(key.clone()).or_insert(Value::Null), value),
+        }
+    }
+}
+
+// apply a JSON Patch (RFC 6902), either every operation succeeds or the target is left untouched
+pub fn json_patch(target: &mut Value, patch: &Value) -> Result<(), PatchError>
+{
+    let operations = patch.as_array().ok_or_else(|| PatchError::Invalid("a JSON Patch must be an array of operations".to_string()))?;
+
+    let mut document = target.clone();
+
+    for (i, operation) in operations.iter().enumerate()
+    {
+        apply_operation(&mut document, operation).map_err(|e| match e
+        {
+            PatchError::Invalid(reason) => PatchError::Invalid(format!("operation {}: {}", i, reason)),
+            PatchError::TestFailed(reason) => PatchError::TestFailed(format!("operation {}: {}", i, reason)),
+        })?;
+    }
+
+    *target = document;
+
+    Ok(())
+}
+
+fn apply_operation(document: &mut Value, operation: &Value) -> Result<(), PatchError>
+{
+    let member = |name: &str| operation.get(name).ok_or_else(|| PatchError::Invalid(format!("missing '{}'", name)));
+    let pointer = |name: &str| member(name)?.as_str().ok_or_else(|| PatchError::Invalid(format!("'{}' must be a string", name)));
+
+    let op = pointer("op")?;
+    let path = pointer("path")?;
+
+    match op
+    {
+        "add" => add(document, path, member("value")?.clone()),
+        "remove" => remove(document, path).map(|_| ()),
+        "replace" =>
+        {
+            let value = member("value")?.clone();
+            let current = get_mut(document, path)?;
+
+            *current = value;
+            Ok(())
+        }
+        "move" =>
+        {
+            let from = pointer("from")?;
+
+            // a location cannot be moved into one of its own children
+            if path.starts_with(from) && path[from.len()..].starts_with('/')
+            {
+                return Err(PatchError::Invalid(format!("cannot move '{}' into itself", from)));
+            }
+
+            let value = remove(document, from)?;
+            add(document, path, value)
+        }
+        "copy" =>
+        {
+            let value = get_mut(document, pointer("from")?)?.clone();
+            add(document, path, value)
+        }
+        "test" =>
+        {
+            let expected = member("value")?;
+
+            match get_mut(document, path)? == expected
+            {
+                true => Ok(()),
+                false => Err(PatchError::TestFailed(format!("value at '{}' does not match", path))),
+            }
+        }
+
+        _ => Err(PatchError::Invalid(format!("unknown op '{}'", op))),
+    }
+}
+
+fn add(document: &mut Value, path: &str, value: Value) -> Result<(), PatchError>
+{
+    if path.is_empty()
+    {
+        *document = value;
+        return Ok(());
+    }
+
+    let (parent, token) = split_pointer(path)?;
+
+    match get_mut(document, parent)?
+    {
+        Value::Object(object) =>
+        {
+            object.insert(token, value);
+            Ok(())
+        }
+        Value::Array(array) =>
+        {
+            let index = match token.as_str()
+            {
+                "-" => array.len(),
+                _ => array_index(&token).filter(|index| *index <= array.len()).ok_or_else(|| not_found(path))?,
+            };
+
+            array.insert(index, value);
+            Ok(())
+        }
+        _ => Err(not_found(path)),
+    }
+}
+
+fn remove(document: &mut Value, path: &str) -> Result<Value, PatchError>
+{
+    let (parent, token) = split_pointer(path)?;
+
+    match get_mut(document, parent)?
+    {
+        Value::Object(object) => object.remove(&token).ok_or_else(|| not_found(path)),
+        Value::Array(array) =>
+        {
+            let index = array_index(&token).filter(|index| *index < array.len()).ok_or_else(|| not_found(path))?;
+
+            Ok(array.remove(index))
+        }
+        _ => Err(not_found(path)),
+    }
+}
+
+fn get_mut<'a>(document: &'a mut Value, path: &str) -> Result<&'a mut Value, PatchError>
+{
+    if !path.is_empty() && !path.starts_with('/')
+    {
+        return Err(PatchError::Invalid(format!("'{}' is not a JSON pointer", path)));
+    }
+
+    document.pointer_mut(path).ok_or_else(|| not_found(path))
+}
+
+// split "/a/b" into the parent pointer "/a" and the unescaped last token "b"
+fn split_pointer(path: &str) -> Result<(&str, String), PatchError>
+{
+    let (parent, token) = path.rsplit_once('/').ok_or_else(|| PatchError::Invalid(format!("'{}' is not a JSON pointer", path)))?;
+
+    Ok((parent, token.replace("~1", "/").replace("~0", "~")))
+}
+
+// array indices are plain decimal numbers without leading zeroes, parse() alone would take "+1" as well
+fn array_index(token: &str) -> Option<usize>
+{
+    match token.bytes().all(|b| b.is_ascii_digit()) && !(token.len() > 1 && token.starts_with('0'))
+    {
+        true => token.parse().ok(),
+        false => None,
+    }
+}
+
+fn not_found(path: &str) -> PatchError
+{
+    PatchError::Invalid(format!("path '{}' does not exist", path))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use serde_json::json;
+
+    use super::*;
+
+    fn patched(mut target: Value, patch: Value) -> Result<Value, PatchError>
+    {
+        json_patch(&mut target, &patch).map(|()| target)
+    }
+
+    fn is_invalid(result: Result<Value, PatchError>) -> bool
+    {
+        matches!(result, Err(PatchError::Invalid(_)))
+    }
+
+    #[test]
+    fn merge_patch_removes_nulls_and_merges_nested_objects()
+    {
+        let mut target = json!({ "name": "Ada", "email": "ada@example.com", "address": { "city": "London", "zip": "N1" } });
+
+        merge_patch(&mut target, &json!({ "email": null, "address": { "zip": null, "street": "Baker St" }, "unknown": null }));
+
+        assert_eq!(target, json!({ "name": "Ada", "address": { "city": "London", "street": "Baker St" } }));
+    }
+
+    #[test]
+    fn merge_patch_replaces_what_is_not_an_object()
+    {
+        let mut target = json!({ "tags": ["a", "b"] });
+
+        merge_patch(&mut target, &json!({ "tags": ["c"] }));
+
+        assert_eq!(target, json!({ "tags": ["c"] }));
+    }
+
+    #[test]
+    fn add_appends_with_a_dash_and_inserts_at_an_index()
+    {
+        let target = json!({ "emails": ["a", "c"] });
+
+        assert_eq!(patched(target.clone(), json!([{ "op": "add", "path": "/emails/-", "value": "d" }])).ok(), Some(json!({ "emails": ["a", "c", "d"] })));
+        assert_eq!(patched(target.clone(), json!([{ "op": "add", "path": "/emails/1", "value": "b" }])).ok(), Some(json!({ "emails": ["a", "b", "c"] })));
+        assert_eq!(patched(target.clone(), json!([{ "op": "add", "path": "/emails/2", "value": "d" }])).ok(), Some(json!({ "emails": ["a", "c", "d"] })));
+        assert!(is_invalid(patched(target, json!([{ "op": "add", "path": "/emails/3", "value": "d" }]))));
+    }
+
+    #[test]
+    fn remove_replace_and_move()
+    {
+        let target = json!({ "name": "Ada", "emails": ["a", "b"], "old": { "city": "London" } });
+
+        let result = patched(target, json!([
+            { "op": "remove", "path": "/emails/0" },
+            { "op": "replace", "path": "/name", "value": "Ada L." },
+            { "op": "move", "from": "/old/city", "path": "/city" },
+        ]));
+
+        assert_eq!(result.ok(), Some(json!({ "name": "Ada L.", "emails": ["b"], "old": {}, "city": "London" })));
+    }
+
+    #[test]
+    fn a_failed_test_leaves_the_target_untouched()
+    {
+        let mut target = json!({ "name": "Ada", "email": "ada@example.com" });
+
+        let result = json_patch(&mut target, &json!([
+            { "op": "replace", "path": "/name", "value": "Grace" },
+            { "op": "test", "path": "/email", "value": "grace@example.com" },
+        ]));
+
+        assert!(matches!(result, Err(PatchError::TestFailed(_))));
+        assert_eq!(target, json!({ "name": "Ada", "email": "ada@example.com" }));
+    }
+
+    #[test]
+    fn pointers_are_unescaped()
+    {
+        let target = json!({ "a/b": 1, "c~d": 2 });
+
+        let result = patched(target, json!([
+            { "op": "remove", "path": "/a~1b" },
+            { "op": "add", "path": "/c~0d", "value": 3 },
+            { "op": "add", "path": "/~01", "value": 4 },
+        ]));
+
+        // "~01" is "~1" and not "/", the escapes are undone in that order
+        assert_eq!(result.ok(), Some(json!({ "c~d": 3, "~1": 4 })));
+    }
+
+    #[test]
+    fn indices_are_plain_decimal_numbers()
+    {
+        let target = json!({ "emails": ["a", "b", "c"] });
+
+        for path in ["/emails/01", "/emails/+1", "/emails/-1", "/emails/ 1", "/emails/"]
+        {
+            assert!(is_invalid(patched(target.clone(), json!([{ "op": "remove", "path": path }]))), "{}", path);
+            assert!(is_invalid(patched(target.clone(), json!([{ "op": "add", "path": path, "value": "x" }]))), "{}", path);
+        }
+
+        assert_eq!(patched(target, json!([{ "op": "remove", "path": "/emails/0" }])).ok(), Some(json!({ "emails": ["b", "c"] })));
+    }
+}
@@ -0,0 +1,1169 @@
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::audit::{self, Action, Actor, Audit, AuditPage, Entry};
+use crate::bans::Ban;
+use crate::error::ApiError;
+use crate::feed::Feed;
+use crate::outbox::{Kind, OutboxEvent};
+use crate::pagination::{AuditQuery, DeliveryQuery, ListQuery, SortField, SortKey};
+use crate::roles::{Role, RoleInfo};
+use crate::timestamp;
+use crate::users::User;
+use crate::webhooks::{Attempt, Delivery, DeliveryPage, DeliveryStatus, NewWebhook, Outcome, Webhook};
+
+use super::{
+    AuditRepository, BanRepository, Change, CredentialRepository, Issue, Lift, NewBan, NewPasswordReset, NewRefreshToken, NewUser, NewVerification, OutboxRepository, Page,
+    PasswordResetRepository, ResetRequest, Restore, RoleRepository, Rotation, SendLimit, TokenRepository, UserRepository, VerificationRepository, WebhookRepository,
+};
+
+// keeps users in process memory, for tests and local development without a database
+pub struct MemoryUserRepository
+{
+    users: Mutex<Vec<Stored>>,
+    refresh_tokens: Mutex<Vec<RefreshToken>>,
+
+    // when each address asked for a password reset, oldest first, locked after the users
+    password_reset_requests: Mutex<Vec<(IpAddr, SystemTime)>>,
+
+    // oldest first, always locked after the users so an entry is added while its change is still in progress
+    audit_log: Mutex<Vec<Entry>>,
+
+    // locked after the users as well
+    outbox: Mutex<Outbox>,
+
+    // locked after the outbox
+    webhooks: Mutex<Webhooks>,
+
+    // told about the events as they are added to the outbox, there is nothing to roll back
+    feed: Feed,
+}
+
+#[derive(Default)]
+struct Outbox
+{
+    // oldest first, delivered events are removed
+    events: Vec<Waiting>,
+    last_id: i64,
+}
+
+// an event in the outbox with its failed attempts, it is due at next_attempt_at
+struct Waiting
+{
+    event: OutboxEvent,
+    attempts: i32,
+    next_attempt_at: SystemTime,
+}
+
+#[derive(Default)]
+struct Webhooks
+{
+    // oldest first
+    webhooks: Vec<Webhook>,
+    deliveries: Vec<Delivery>,
+    last_delivery_id: i64,
+}
+
+impl Webhooks
+{
+    fn queue(&mut self, webhook_id: Uuid, event_id: Option<i64>, event: &str, payload: serde_json::Value, next_attempt_at: SystemTime) -> Delivery
+    {
+        self.last_delivery_id += 1;
+
+        let delivery = Delivery
+        {
+            id: self.last_delivery_id,
+            webhook_id,
+            event_id,
+            event: event.to_string(),
+            payload,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: Some(next_attempt_at),
+            response_status: None,
+            last_error: None,
+            created_at: timestamp::now(),
+            delivered_at: None,
+        };
+
+        self.deliveries.push(delivery.clone());
+
+        delivery
+    }
+}
+
+struct RefreshToken
+{
+    token: NewRefreshToken,
+    used: bool,
+    revoked: bool,
+}
+
+struct Stored
+{
+    user: User,
+    password_hash: Option<String>,
+
+    // oldest first
+    bans: Vec<Ban>,
+
+    // oldest first
+    verifications: Vec<EmailToken>,
+
+    // oldest first
+    password_resets: Vec<EmailToken>,
+}
+
+struct EmailToken
+{
+    hash: String,
+    email: String,
+    created_at: SystemTime,
+    expires_at: SystemTime,
+}
+
+impl Stored
+{
+    fn id(&self) -> Uuid
+    {
+        self.user.id.unwrap_or_default()
+    }
+
+    // the user with this id, unless they have been deleted
+    fn is(&self, id: Uuid) -> bool
+    {
+        self.id() == id && self.user.deleted_at.is_none()
+    }
+
+    fn created_at(&self) -> SystemTime
+    {
+        self.user.created_at.unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    fn banned(&self) -> bool
+    {
+        let now = SystemTime::now();
+
+        self.bans.iter().any(|ban| ban.is_active(now))
+    }
+
+    // the user as it is read, with the banned flag as of now
+    fn user(&self) -> User
+    {
+        User { banned: Some(self.banned()), ..self.user.clone() }
+    }
+
+    fn sort_key(&self, sort: SortField) -> SortKey
+    {
+        match sort
+        {
+            SortField::Name => SortKey::Text(self.user.name.clone()),
+            SortField::Email => SortKey::Text(self.user.email.clone()),
+            SortField::Created => SortKey::Time(self.created_at()),
+        }
+    }
+
+    fn matches(&self, query: &ListQuery) -> bool
+    {
+        let domain = self.user.email.rsplit_once('@').map(|(_, domain)| domain).unwrap_or_default();
+
+        (query.include_deleted || self.user.deleted_at.is_none())
+            && query.role.as_ref().is_none_or(|role| self.user.roles.as_ref().is_some_and(|roles| roles.contains(role)))
+            && query.banned.is_none_or(|banned| self.banned() == banned)
+            && query.email_domain.as_ref().is_none_or(|wanted| domain.eq_ignore_ascii_case(wanted))
+            && query.created_after.is_none_or(|after| self.created_at() > after)
+            && query.created_before.is_none_or(|before| self.created_at() < before)
+    }
+}
+
+impl MemoryUserRepository
+{
+    pub fn new(feed: Feed) -> Self
+    {
+        MemoryUserRepository
+        {
+            users: Mutex::default(),
+            refresh_tokens: Mutex::default(),
+            password_reset_requests: Mutex::default(),
+            audit_log: Mutex::default(),
+            outbox: Mutex::default(),
+            webhooks: Mutex::default(),
+            feed,
+        }
+    }
+
+    fn record(&self, audit: &Audit, action: Action, user_id: Uuid, changes: serde_json::Map<String, serde_json::Value>)
+    {
+        let mut audit_log = self.audit_log.lock().unwrap();
+
+        let entry = Entry
+        {
+            id: audit_log.len() as i64 + 1,
+            actor: audit.actor.clone(),
+            action,
+            user_id,
+            changes: serde_json::Value::Object(changes),
+            request_id: audit.request_id.clone(),
+            source_ip: audit.source_ip,
+            created_at: timestamp::now(),
+        };
+
+        audit_log.push(entry);
+    }
+
+    fn publish<T: Serialize>(&self, kind: Kind, user_id: Uuid, data: &T)
+    {
+        let mut outbox = self.outbox.lock().unwrap();
+
+        outbox.last_id += 1;
+
+        let event = OutboxEvent { id: outbox.last_id, kind, user_id, data: serde_json::to_value(data).unwrap(), occurred_at: timestamp::now() };
+
+        let mut webhooks = self.webhooks.lock().unwrap();
+
+        let subscribed: Vec<Uuid> = webhooks.webhooks.iter().filter(|webhook| webhook.events.iter().any(|topic| topic == kind.topic())).map(|webhook| webhook.id).collect();
+
+        for webhook_id in subscribed
+        {
+            webhooks.queue(webhook_id, Some(event.id), kind.topic(), serde_json::to_value(&event).unwrap(), event.occurred_at);
+        }
+
+        // still under the lock of the outbox so the feed sees the events in order
+        self.feed.publish(event.clone());
+
+        outbox.events.push(Waiting { event, attempts: 0, next_attempt_at: SystemTime::now() });
+    }
+
+    // false if the user does not exist
+    fn change_roles(&self, user_id: Uuid, action: Action, audit: &Audit, change: impl FnOnce(&mut Vec<String>)) -> bool
+    {
+        let mut users = self.users.lock().unwrap();
+
+        let stored = match users.iter_mut().find(|stored| stored.is(user_id))
+        {
+            Some(stored) => stored,
+            None => return false,
+        };
+
+        let before = stored.user();
+
+        change(stored.user.roles.get_or_insert_with(Vec::new));
+
+        let after = stored.user();
+        let changes = audit::diff(Some(&before), Some(&after));
+
+        if !changes.is_empty()
+        {
+            self.record(audit, action, user_id, changes);
+            self.publish(Kind::Updated, user_id, &after);
+        }
+
+        true
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryUserRepository
+{
+    async fn get(&self, id: Uuid) -> Result<Option<User>, ApiError>
+    {
+        let users = self.users.lock().unwrap();
+
+        Ok(users.iter().find(|stored| stored.is(id)).map(Stored::user))
+    }
+
+    async fn get_including_deleted(&self, id: Uuid) -> Result<Option<User>, ApiError>
+    {
+        let users = self.users.lock().unwrap();
+
+        Ok(users.iter().find(|stored| stored.id() == id).map(Stored::user))
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<Page, ApiError>
+    {
+        let after = match &query.after
+        {
+            Some(cursor) => Some((cursor.key()?, cursor.id)),
+            None => None,
+        };
+
+        let users = self.users.lock().unwrap();
+
+        let mut page: Vec<(SortKey, Uuid, &Stored)> = users.iter()
+            .filter(|stored| stored.matches(query))
+            .map(|stored| (stored.sort_key(query.sort), stored.id(), stored))
+            .collect();
+
+        // same order as ORDER BY column, id with the id breaking ties, except that names and emails are compared
+        // byte by byte where PostgreSQL uses the collation of the database
+        page.sort_by(|a, b| match query.descending
+        {
+            true => (&b.0, b.1).cmp(&(&a.0, a.1)),
+            false => (&a.0, a.1).cmp(&(&b.0, b.1)),
+        });
+
+        if let Some((key, id)) = &after
+        {
+            page.retain(|(k, i, _)| match query.descending
+            {
+                true => (k, i) < (key, id),
+                false => (k, i) > (key, id),
+            });
+        }
+
+        let limit = query.limit as usize;
+
+        let next = match page.len() > limit
+        {
+            true =>
+            {
+                page.truncate(limit);
+                page.last().map(|(key, id, _)| query.next_link(*id, key.clone()))
+            }
+            false => None,
+        };
+
+        Ok(Page { users: page.into_iter().map(|(_, _, stored)| stored.user()).collect(), next })
+    }
+
+    async fn create(&self, user: NewUser, audit: &Audit) -> Result<User, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+
+        if users.iter().any(|stored| stored.user.email == user.email)
+        {
+            return Err(ApiError::DuplicateEmail);
+        }
+
+        let now = timestamp::now();
+
+        let created = User
+        {
+            id: Some(Uuid::new_v4()),
+            name: user.name,
+            email: user.email,
+            email_verified: Some(false),
+            roles: Some(vec![Role::DEFAULT.name().to_string()]),
+            banned: Some(false),
+            created_at: Some(now),
+            updated_at: Some(now),
+            last_login_at: None,
+            deleted_at: None,
+        };
+
+        users.push(Stored { user: created.clone(), password_hash: None, bans: Vec::new(), verifications: Vec::new(), password_resets: Vec::new() });
+
+        self.record(audit, Action::Created, created.id.unwrap_or_default(), audit::diff(None, Some(&created)));
+        self.publish(Kind::Created, created.id.unwrap_or_default(), &created);
+
+        Ok(created)
+    }
+
+    async fn update(&self, id: Uuid, change: Change<'_>, audit: &Audit) -> Result<Option<User>, ApiError>
+    {
+        // holding the lock for the whole update makes it atomic
+        let mut users = self.users.lock().unwrap();
+
+        let index = match users.iter().position(|stored| stored.is(id))
+        {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        let current = users[index].user();
+        let changes = change(&current)?;
+
+        if let Some(email) = &changes.email
+        {
+            if users.iter().any(|stored| stored.id() != id && &stored.user.email == email)
+            {
+                return Err(ApiError::DuplicateEmail);
+            }
+        }
+
+        let stored = &mut users[index];
+        let before = (stored.user.name.clone(), stored.user.email.clone());
+
+        if let Some(name) = changes.name
+        {
+            stored.user.name = name;
+        }
+
+        if let Some(email) = changes.email
+        {
+            stored.user.email = email;
+        }
+
+        // a new email has to be verified again
+        if stored.user.email != before.1
+        {
+            stored.user.email_verified = Some(false);
+        }
+
+        // writing the same values again is not a change, like the trigger in PostgreSQL
+        if (&stored.user.name, &stored.user.email) != (&before.0, &before.1)
+        {
+            stored.user.updated_at = Some(timestamp::now());
+        }
+
+        let updated = stored.user();
+        let changes = audit::diff(Some(&current), Some(&updated));
+
+        if !changes.is_empty()
+        {
+            self.record(audit, Action::Updated, id, changes);
+            self.publish(Kind::Updated, id, &updated);
+        }
+
+        Ok(Some(updated))
+    }
+
+    async fn delete(&self, id: Uuid, audit: &Audit) -> Result<bool, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+
+        let stored = match users.iter_mut().find(|stored| stored.is(id))
+        {
+            Some(stored) => stored,
+            None => return Ok(false),
+        };
+
+        let before = stored.user();
+
+        stored.user.deleted_at = Some(timestamp::now());
+
+        self.refresh_tokens.lock().unwrap().iter_mut().filter(|stored| stored.token.user_id == id).for_each(|stored| stored.revoked = true);
+        let deleted = stored.user();
+
+        self.record(audit, Action::Deleted, id, audit::diff(Some(&before), Some(&deleted)));
+        self.publish(Kind::Deleted, id, &deleted);
+
+        Ok(true)
+    }
+
+    async fn restore(&self, id: Uuid, deleted_after: SystemTime, audit: &Audit) -> Result<Restore, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+
+        let stored = match users.iter_mut().find(|stored| stored.id() == id)
+        {
+            Some(stored) => stored,
+            None => return Ok(Restore::NotFound),
+        };
+
+        match stored.user.deleted_at
+        {
+            Some(deleted_at) if deleted_at > deleted_after =>
+            {
+                let before = stored.user();
+
+                stored.user.deleted_at = None;
+
+                let restored = stored.user();
+
+                self.record(audit, Action::Restored, id, audit::diff(Some(&before), Some(&restored)));
+                self.publish(Kind::Updated, id, &restored);
+
+                Ok(Restore::Restored(restored))
+            }
+            Some(_) => Ok(Restore::Expired),
+            None => Ok(Restore::NotDeleted),
+        }
+    }
+
+    async fn purge(&self, deleted_before: SystemTime, audit: &Audit) -> Result<Vec<Uuid>, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+
+        let purged: Vec<Uuid> = users.iter().filter(|stored| stored.user.deleted_at.is_some_and(|deleted_at| deleted_at <= deleted_before)).map(Stored::id).collect();
+
+        users.retain(|stored| !purged.contains(&stored.id()));
+
+        // tokens go away with their user, like the foreign key does in Postgres
+        self.refresh_tokens.lock().unwrap().retain(|stored| !purged.contains(&stored.token.user_id));
+
+        for id in &purged
+        {
+            self.record(audit, Action::Purged, *id, serde_json::Map::new());
+        }
+
+        Ok(purged)
+    }
+
+    async fn exists_by_email(&self, email: &str) -> Result<bool, ApiError>
+    {
+        let users = self.users.lock().unwrap();
+
+        Ok(users.iter().any(|stored| stored.user.email == email))
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, ApiError>
+    {
+        let users = self.users.lock().unwrap();
+
+        Ok(users.iter().find(|stored| stored.user.email == email && stored.user.deleted_at.is_none()).map(Stored::user))
+    }
+
+    async fn record_login(&self, id: Uuid) -> Result<Option<User>, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+
+        match users.iter_mut().find(|stored| stored.is(id))
+        {
+            Some(stored) =>
+            {
+                stored.user.last_login_at = Some(timestamp::now());
+                Ok(Some(stored.user()))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl CredentialRepository for MemoryUserRepository
+{
+    async fn password_hash(&self, user_id: Uuid) -> Result<Option<String>, ApiError>
+    {
+        let users = self.users.lock().unwrap();
+
+        Ok(users.iter().find(|stored| stored.is(user_id)).and_then(|stored| stored.password_hash.clone()))
+    }
+
+    async fn set_password_hash(&self, user_id: Uuid, hash: &str, audit: Option<&Audit>) -> Result<bool, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+
+        match users.iter_mut().find(|stored| stored.is(user_id))
+        {
+            Some(stored) =>
+            {
+                stored.password_hash = Some(hash.to_string());
+
+                if let Some(audit) = audit
+                {
+                    self.record(audit, Action::PasswordChanged, user_id, serde_json::Map::new());
+                }
+
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[async_trait]
+impl BanRepository for MemoryUserRepository
+{
+    async fn create_ban(&self, ban: NewBan, audit: &Audit) -> Result<Option<Ban>, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+
+        let stored = match users.iter_mut().find(|stored| stored.is(ban.user_id))
+        {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+
+        let created = Ban
+        {
+            id: Uuid::new_v4(),
+            user_id: ban.user_id,
+            moderator_id: ban.moderator_id,
+            reason: ban.reason,
+            created_at: timestamp::now(),
+            expires_at: ban.expires_at,
+            lifted_at: None,
+            lifted_by: None,
+            active: true,
+        };
+
+        let before = stored.user();
+
+        stored.bans.push(created.clone());
+
+        let mut changes = audit::diff(Some(&before), Some(&stored.user()));
+        changes.insert("ban".to_string(), audit::change(None, Some(&created)));
+
+        self.record(audit, Action::Banned, ban.user_id, changes);
+        self.publish(Kind::Banned, ban.user_id, &created);
+
+        Ok(Some(created))
+    }
+
+    async fn bans(&self, user_id: Uuid) -> Result<Option<Vec<Ban>>, ApiError>
+    {
+        let users = self.users.lock().unwrap();
+        let now = SystemTime::now();
+
+        Ok(users.iter().find(|stored| stored.is(user_id)).map(|stored| stored.bans.iter().rev().map(|ban| Ban { active: ban.is_active(now), ..ban.clone() }).collect()))
+    }
+
+    async fn lift_ban(&self, user_id: Uuid, ban_id: Uuid, lifted_by: Option<Uuid>, audit: &Audit) -> Result<Lift, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+        let now = timestamp::now();
+
+        let stored = match users.iter_mut().find(|stored| stored.is(user_id))
+        {
+            Some(stored) => stored,
+            None => return Ok(Lift::NotFound),
+        };
+
+        let before = stored.user();
+
+        let ban = match stored.bans.iter_mut().find(|ban| ban.id == ban_id)
+        {
+            Some(ban) => ban,
+            None => return Ok(Lift::NotFound),
+        };
+
+        if !ban.is_active(now)
+        {
+            return Ok(Lift::Inactive);
+        }
+
+        ban.lifted_at = Some(now);
+        ban.lifted_by = lifted_by;
+        ban.active = false;
+
+        let lifted = ban.clone();
+        let after = stored.user();
+
+        let mut changes = audit::diff(Some(&before), Some(&after));
+        changes.insert("ban".to_string(), audit::change(Some(&lifted.unlifted()), Some(&lifted)));
+
+        self.record(audit, Action::Unbanned, user_id, changes);
+        self.publish(Kind::Unbanned, user_id, &lifted);
+
+        Ok(Lift::Lifted)
+    }
+
+    async fn lift_expired(&self, audit: &Audit) -> Result<Vec<Ban>, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+        let now = SystemTime::now();
+
+        let mut lifted = Vec::new();
+
+        for stored in users.iter_mut()
+        {
+            let start = lifted.len();
+
+            for ban in stored.bans.iter_mut()
+            {
+                if let Some(expires_at) = ban.expires_at.filter(|expires_at| ban.lifted_at.is_none() && *expires_at <= now)
+                {
+                    ban.lifted_at = Some(expires_at);
+                    ban.active = false;
+                    lifted.push(ban.clone());
+                }
+            }
+
+            // the user stopped being banned when the bans expired, so only the bans themselves change here
+            for ban in &lifted[start..]
+            {
+                let mut changes = serde_json::Map::new();
+                changes.insert("ban".to_string(), audit::change(Some(&ban.unlifted()), Some(ban)));
+
+                self.record(audit, Action::Unbanned, ban.user_id, changes);
+
+                // deleted users are gone for the consumers already
+                if stored.user.deleted_at.is_none()
+                {
+                    self.publish(Kind::Unbanned, ban.user_id, ban);
+                }
+            }
+        }
+
+        Ok(lifted)
+    }
+}
+
+#[async_trait]
+impl VerificationRepository for MemoryUserRepository
+{
+    async fn create_verification(&self, token: NewVerification, limit: SendLimit) -> Result<Issue, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+
+        let stored = match users.iter_mut().find(|stored| stored.is(token.user_id))
+        {
+            Some(stored) => stored,
+            None => return Ok(Issue::NotFound),
+        };
+
+        if stored.user.email_verified == Some(true)
+        {
+            return Ok(Issue::AlreadyVerified);
+        }
+
+        let now = timestamp::now();
+
+        stored.verifications.retain(|sent| sent.expires_at > now || sent.created_at + limit.retention() > now);
+
+        let sent: Vec<SystemTime> = stored.verifications.iter().map(|sent| sent.created_at).collect();
+
+        if let Some(wait) = limit.wait(&sent, now)
+        {
+            return Ok(Issue::Throttled(wait));
+        }
+
+        stored.verifications.push(EmailToken { hash: token.hash, email: stored.user.email.clone(), created_at: now, expires_at: token.expires_at });
+
+        Ok(Issue::Issued(stored.user()))
+    }
+
+    async fn verify_email(&self, user_id: Uuid, hash: &str, audit: &Audit) -> Result<Option<User>, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+
+        let stored = match users.iter_mut().find(|stored| stored.is(user_id))
+        {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+
+        let now = SystemTime::now();
+
+        if !stored.verifications.iter().any(|sent| sent.hash == hash && sent.email == stored.user.email && sent.expires_at > now)
+        {
+            return Ok(None);
+        }
+
+        let before = stored.user();
+
+        stored.user.email_verified = Some(true);
+
+        // the other tokens could only verify the same email again
+        stored.verifications.clear();
+
+        let after = stored.user();
+        let changes = audit::diff(Some(&before), Some(&after));
+
+        if !changes.is_empty()
+        {
+            self.record(audit, Action::EmailVerified, user_id, changes);
+            self.publish(Kind::Updated, user_id, &after);
+        }
+
+        Ok(Some(after))
+    }
+}
+
+#[async_trait]
+impl PasswordResetRepository for MemoryUserRepository
+{
+    async fn create_password_reset(&self, token: NewPasswordReset, user_limit: SendLimit, ip_limit: SendLimit) -> Result<ResetRequest, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+        let mut requests = self.password_reset_requests.lock().unwrap();
+
+        let now = timestamp::now();
+
+        requests.retain(|(_, at)| *at + ip_limit.retention() > now);
+
+        let asked: Vec<SystemTime> = requests.iter().filter(|(ip, _)| *ip == token.source_ip).map(|(_, at)| *at).collect();
+
+        if let Some(wait) = ip_limit.wait(&asked, now)
+        {
+            return Ok(ResetRequest::Throttled(wait));
+        }
+
+        // from here on the request counts against the address whatever the outcome
+        requests.push((token.source_ip, now));
+
+        let stored = match users.iter_mut().find(|stored| stored.user.deleted_at.is_none() && stored.user.email == token.email)
+        {
+            Some(stored) => stored,
+            None => return Ok(ResetRequest::Ignored),
+        };
+
+        stored.password_resets.retain(|sent| sent.expires_at > now || sent.created_at + user_limit.retention() > now);
+
+        let sent: Vec<SystemTime> = stored.password_resets.iter().map(|sent| sent.created_at).collect();
+
+        if user_limit.wait(&sent, now).is_some()
+        {
+            return Ok(ResetRequest::Ignored);
+        }
+
+        stored.password_resets.push(EmailToken { hash: token.hash, email: stored.user.email.clone(), created_at: now, expires_at: token.expires_at });
+
+        Ok(ResetRequest::Issued(stored.user()))
+    }
+
+    async fn reset_password(&self, hash: &str, password_hash: &str, audit: &Audit) -> Result<bool, ApiError>
+    {
+        let mut users = self.users.lock().unwrap();
+
+        let now = SystemTime::now();
+
+        let stored = match users.iter_mut().find(|stored| stored.user.deleted_at.is_none() && stored.password_resets.iter().any(|sent| sent.hash == hash))
+        {
+            Some(stored) => stored,
+            None => return Ok(false),
+        };
+
+        if !stored.password_resets.iter().any(|sent| sent.hash == hash && sent.email == stored.user.email && sent.expires_at > now)
+        {
+            return Ok(false);
+        }
+
+        stored.password_hash = Some(password_hash.to_string());
+
+        // whoever knew the old password is logged out everywhere, the other tokens could only do the same again
+        stored.password_resets.clear();
+
+        let id = stored.id();
+
+        self.refresh_tokens.lock().unwrap().iter_mut().filter(|stored| stored.token.user_id == id).for_each(|stored| stored.revoked = true);
+
+        self.record(audit, Action::PasswordChanged, id, serde_json::Map::new());
+
+        Ok(true)
+    }
+}
+
+// only the built-in roles exist without a database
+#[async_trait]
+impl RoleRepository for MemoryUserRepository
+{
+    async fn list_roles(&self) -> Result<Vec<RoleInfo>, ApiError>
+    {
+        let mut roles: Vec<RoleInfo> = Role::ALL.into_iter().map(Role::info).collect();
+
+        roles.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(roles)
+    }
+
+    async fn assign_role(&self, user_id: Uuid, role: &str, audit: &Audit) -> Result<bool, ApiError>
+    {
+        let role = Role::from_name(role).ok_or(ApiError::RoleNotFound)?;
+
+        Ok(self.change_roles(user_id, Action::RoleAssigned, audit, |roles| if !roles.iter().any(|name| name == role.name())
+        {
+            roles.push(role.name().to_string());
+            roles.sort();
+        }))
+    }
+
+    async fn revoke_role(&self, user_id: Uuid, role: &str, audit: &Audit) -> Result<bool, ApiError>
+    {
+        let role = Role::from_name(role).ok_or(ApiError::RoleNotFound)?;
+
+        Ok(self.change_roles(user_id, Action::RoleRevoked, audit, |roles| roles.retain(|name| name != role.name())))
+    }
+
+    async fn permissions(&self, roles: &[String]) -> Result<Vec<String>, ApiError>
+    {
+        let mut permissions: Vec<String> = roles.iter()
+            .filter_map(|name| Role::from_name(name))
+            .flat_map(|role| role.permissions().iter().map(|permission| permission.name().to_string()))
+            .collect();
+
+        permissions.sort();
+        permissions.dedup();
+
+        Ok(permissions)
+    }
+}
+
+#[async_trait]
+impl TokenRepository for MemoryUserRepository
+{
+    async fn create_refresh_token(&self, token: NewRefreshToken) -> Result<(), ApiError>
+    {
+        self.refresh_tokens.lock().unwrap().push(RefreshToken { token, used: false, revoked: false });
+
+        Ok(())
+    }
+
+    async fn rotate_refresh_token(&self, hash: &str, replacement: &str, expires_at: SystemTime) -> Result<Rotation, ApiError>
+    {
+        let mut tokens = self.refresh_tokens.lock().unwrap();
+
+        let stored = match tokens.iter_mut().find(|stored| stored.token.hash == hash)
+        {
+            Some(stored) => stored,
+            None => return Ok(Rotation::Invalid),
+        };
+
+        if stored.revoked || stored.token.expires_at <= SystemTime::now()
+        {
+            return Ok(Rotation::Invalid);
+        }
+
+        let (user_id, family) = (stored.token.user_id, stored.token.family);
+
+        if stored.used
+        {
+            tokens.iter_mut().filter(|stored| stored.token.family == family).for_each(|stored| stored.revoked = true);
+
+            return Ok(Rotation::Reused);
+        }
+
+        stored.used = true;
+
+        tokens.push(RefreshToken { token: NewRefreshToken { hash: replacement.to_string(), user_id, family, expires_at }, used: false, revoked: false });
+
+        Ok(Rotation::Rotated(user_id))
+    }
+
+    async fn revoke_family(&self, hash: &str) -> Result<(), ApiError>
+    {
+        let mut tokens = self.refresh_tokens.lock().unwrap();
+
+        if let Some(family) = tokens.iter().find(|stored| stored.token.hash == hash).map(|stored| stored.token.family)
+        {
+            tokens.iter_mut().filter(|stored| stored.token.family == family).for_each(|stored| stored.revoked = true);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AuditRepository for MemoryUserRepository
+{
+    async fn audit_log(&self, query: &AuditQuery) -> Result<AuditPage, ApiError>
+    {
+        let audit_log = self.audit_log.lock().unwrap();
+
+        let mut entries: Vec<Entry> = audit_log.iter().rev()
+            .filter(|entry| query.after.is_none_or(|after| entry.id < after))
+            .filter(|entry| query.user_id.is_none_or(|id| entry.user_id == id))
+            .filter(|entry| query.actor_id.is_none_or(|id| matches!(entry.actor, Actor::User { id: actor } if actor == id)))
+            .filter(|entry| query.action.is_none_or(|action| entry.action == action))
+            .filter(|entry| query.request_id.as_ref().is_none_or(|id| entry.request_id.as_ref() == Some(id)))
+            .filter(|entry| query.created_after.is_none_or(|after| entry.created_at > after))
+            .filter(|entry| query.created_before.is_none_or(|before| entry.created_at < before))
+            .take(query.limit as usize + 1)
+            .cloned()
+            .collect();
+
+        let next = match entries.len() > query.limit as usize
+        {
+            true =>
+            {
+                entries.truncate(query.limit as usize);
+                entries.last().map(|last| query.next_link(last.id))
+            }
+            false => None,
+        };
+
+        Ok(AuditPage { entries, next })
+    }
+}
+
+#[async_trait]
+impl OutboxRepository for MemoryUserRepository
+{
+    async fn claim_events(&self, limit: usize, lease: Duration) -> Result<Vec<(OutboxEvent, i32)>, ApiError>
+    {
+        let mut outbox = self.outbox.lock().unwrap();
+        let now = SystemTime::now();
+
+        // users with an event that is claimed or waiting for a retry, their later events stay where they are
+        let mut held = Vec::new();
+        let mut claimed = Vec::new();
+
+        for waiting in outbox.events.iter_mut()
+        {
+            if claimed.len() == limit
+            {
+                break;
+            }
+
+            if held.contains(&waiting.event.user_id)
+            {
+                continue;
+            }
+
+            match waiting.next_attempt_at <= now
+            {
+                true =>
+                {
+                    waiting.next_attempt_at = now + lease;
+                    claimed.push((waiting.event.clone(), waiting.attempts));
+                }
+                false => held.push(waiting.event.user_id),
+            }
+        }
+
+        Ok(claimed)
+    }
+
+    async fn delivered(&self, id: i64) -> Result<(), ApiError>
+    {
+        self.outbox.lock().unwrap().events.retain(|waiting| waiting.event.id != id);
+
+        Ok(())
+    }
+
+    // the error is only kept in PostgreSQL, for whoever looks at the table
+    async fn failed(&self, id: i64, _error: &str, retry_at: SystemTime) -> Result<(), ApiError>
+    {
+        let mut outbox = self.outbox.lock().unwrap();
+        let now = SystemTime::now();
+
+        let user_id = match outbox.events.iter_mut().find(|waiting| waiting.event.id == id)
+        {
+            Some(waiting) =>
+            {
+                waiting.attempts += 1;
+                waiting.next_attempt_at = retry_at;
+                waiting.event.user_id
+            }
+            None => return Ok(()),
+        };
+
+        // the later events of the user claimed along with it are handed back, they wait behind it now
+        for waiting in outbox.events.iter_mut().filter(|waiting| waiting.event.user_id == user_id && waiting.event.id > id)
+        {
+            waiting.next_attempt_at = now;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl WebhookRepository for MemoryUserRepository
+{
+    async fn create_webhook(&self, webhook: NewWebhook) -> Result<Webhook, ApiError>
+    {
+        let webhook = Webhook { id: Uuid::new_v4(), url: webhook.url, events: webhook.events, secret: webhook.secret, created_at: timestamp::now() };
+
+        self.webhooks.lock().unwrap().webhooks.push(webhook.clone());
+
+        Ok(webhook)
+    }
+
+    async fn webhooks(&self) -> Result<Vec<Webhook>, ApiError>
+    {
+        Ok(self.webhooks.lock().unwrap().webhooks.clone())
+    }
+
+    async fn webhook(&self, id: Uuid) -> Result<Option<Webhook>, ApiError>
+    {
+        Ok(self.webhooks.lock().unwrap().webhooks.iter().find(|webhook| webhook.id == id).cloned())
+    }
+
+    async fn delete_webhook(&self, id: Uuid) -> Result<bool, ApiError>
+    {
+        let mut webhooks = self.webhooks.lock().unwrap();
+
+        let count = webhooks.webhooks.len();
+
+        webhooks.webhooks.retain(|webhook| webhook.id != id);
+        webhooks.deliveries.retain(|delivery| delivery.webhook_id != id);
+
+        Ok(webhooks.webhooks.len() < count)
+    }
+
+    async fn deliveries(&self, webhook_id: Uuid, query: &DeliveryQuery) -> Result<Option<DeliveryPage>, ApiError>
+    {
+        let webhooks = self.webhooks.lock().unwrap();
+
+        if !webhooks.webhooks.iter().any(|webhook| webhook.id == webhook_id)
+        {
+            return Ok(None);
+        }
+
+        let mut deliveries: Vec<Delivery> = webhooks.deliveries.iter().rev()
+            .filter(|delivery| delivery.webhook_id == webhook_id)
+            .filter(|delivery| query.after.is_none_or(|after| delivery.id < after))
+            .filter(|delivery| query.status.is_none_or(|status| delivery.status == status))
+            .take(query.limit as usize + 1)
+            .cloned()
+            .collect();
+
+        let next = match deliveries.len() > query.limit as usize
+        {
+            true =>
+            {
+                deliveries.truncate(query.limit as usize);
+                deliveries.last().map(|last| query.next_link(last.id))
+            }
+            false => None,
+        };
+
+        Ok(Some(DeliveryPage { deliveries, next }))
+    }
+
+    async fn create_ping(&self, webhook_id: Uuid, payload: serde_json::Value, lease: Duration) -> Result<Option<(Webhook, Delivery)>, ApiError>
+    {
+        let mut webhooks = self.webhooks.lock().unwrap();
+
+        let webhook = match webhooks.webhooks.iter().find(|webhook| webhook.id == webhook_id)
+        {
+            Some(webhook) => webhook.clone(),
+            None => return Ok(None),
+        };
+
+        let delivery = webhooks.queue(webhook_id, None, "ping", payload, SystemTime::now() + lease);
+
+        Ok(Some((webhook, delivery)))
+    }
+
+    async fn claim_deliveries(&self, limit: usize, lease: Duration) -> Result<Vec<(Webhook, Delivery)>, ApiError>
+    {
+        let mut webhooks = self.webhooks.lock().unwrap();
+        let Webhooks { webhooks, deliveries, .. } = &mut *webhooks;
+
+        let now = SystemTime::now();
+
+        let mut due: Vec<&mut Delivery> = deliveries.iter_mut()
+            .filter(|delivery| delivery.status == DeliveryStatus::Pending && delivery.next_attempt_at.is_some_and(|at| at <= now))
+            .collect();
+
+        due.sort_by_key(|delivery| (delivery.next_attempt_at, delivery.id));
+
+        let claimed = due.into_iter().take(limit).filter_map(|delivery|
+        {
+            delivery.next_attempt_at = Some(now + lease);
+
+            let webhook = webhooks.iter().find(|webhook| webhook.id == delivery.webhook_id)?;
+
+            Some((webhook.clone(), delivery.clone()))
+        });
+
+        Ok(claimed.collect())
+    }
+
+    async fn record_attempt(&self, id: i64, attempt: &Attempt) -> Result<Option<Delivery>, ApiError>
+    {
+        let mut webhooks = self.webhooks.lock().unwrap();
+
+        let delivery = match webhooks.deliveries.iter_mut().find(|delivery| delivery.id == id)
+        {
+            Some(delivery) => delivery,
+            None => return Ok(None),
+        };
+
+        (delivery.status, delivery.next_attempt_at) = match attempt.outcome
+        {
+            Outcome::Delivered => (DeliveryStatus::Delivered, None),
+            Outcome::Retry(at) => (DeliveryStatus::Pending, Some(at)),
+            Outcome::Dead => (DeliveryStatus::Dead, None),
+        };
+
+        delivery.attempts += 1;
+        delivery.response_status = attempt.response_status;
+        delivery.last_error = attempt.error.clone();
+
+        if delivery.status == DeliveryStatus::Delivered
+        {
+            delivery.delivered_at = Some(timestamp::now());
+        }
+
+        Ok(Some(delivery.clone()))
+    }
+}
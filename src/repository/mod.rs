@@ -0,0 +1,369 @@
+mod memory;
+mod postgres;
+
+#[cfg(test)]
+mod tests;
+
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::audit::{Audit, AuditPage};
+use crate::error::ApiError;
+use crate::bans::Ban;
+use crate::outbox::OutboxEvent;
+use crate::pagination::{AuditQuery, DeliveryQuery, ListQuery};
+use crate::roles::RoleInfo;
+use crate::users::User;
+use crate::webhooks::{Attempt, Delivery, DeliveryPage, NewWebhook, Webhook};
+
+pub use memory::MemoryUserRepository;
+pub use postgres::PostgresUserRepository;
+
+pub struct NewUser
+{
+    pub name: String,
+    pub email: String,
+}
+
+// columns to write, None leaves the current value alone
+#[derive(Clone, Default)]
+pub struct UserChanges
+{
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+impl UserChanges
+{
+    pub fn is_empty(&self) -> bool
+    {
+        self.name.is_none() && self.email.is_none()
+    }
+}
+
+pub struct Page
+{
+    pub users: Vec<User>,
+
+    // link to the following page, None on the last one
+    pub next: Option<String>,
+}
+
+// computes the changes to make from the current state of a user
+pub type Change<'a> = &'a (dyn Fn(&User) -> Result<UserChanges, ApiError> + Sync);
+
+pub enum Restore
+{
+    Restored(User),
+
+    // the user was never deleted or has been restored already
+    NotDeleted,
+
+    // deleted too long ago, the user is waiting to be purged
+    Expired,
+
+    NotFound,
+}
+
+// storage for users, every implementation must behave the same way:
+// emails are unique (DuplicateEmail), new users get the default role, no bans and an unverified email,
+// changing the email makes it unverified again (writing the same one is no change),
+// created_at and updated_at are set on insert and updated_at again whenever the name or email changes,
+// operations on a missing id return None/false instead of an error, and every change is written to
+// the audit log and the outbox together with the change itself (nothing is written when nothing changes).
+// deleted users count as missing everywhere except get_including_deleted, list with include_deleted,
+// restore and purge, but keep their email until they are purged
+#[async_trait]
+pub trait UserRepository: Send + Sync
+{
+    async fn get(&self, id: Uuid) -> Result<Option<User>, ApiError>;
+
+    // like get, but also finds a user that has been deleted and not purged yet
+    async fn get_including_deleted(&self, id: Uuid) -> Result<Option<User>, ApiError>;
+
+    async fn list(&self, query: &ListQuery) -> Result<Page, ApiError>;
+
+    async fn create(&self, user: NewUser, audit: &Audit) -> Result<User, ApiError>;
+
+    // the change is computed and applied atomically, nobody else can modify the user in between
+    async fn update(&self, id: Uuid, change: Change<'_>, audit: &Audit) -> Result<Option<User>, ApiError>;
+
+    // mark the user as deleted and revoke their refresh tokens, false if there was nobody to delete
+    async fn delete(&self, id: Uuid, audit: &Audit) -> Result<bool, ApiError>;
+
+    // undo a delete made after the given time
+    async fn restore(&self, id: Uuid, deleted_after: SystemTime, audit: &Audit) -> Result<Restore, ApiError>;
+
+    // remove every user deleted at or before the given time for good, returning their ids
+    async fn purge(&self, deleted_before: SystemTime, audit: &Audit) -> Result<Vec<Uuid>, ApiError>;
+
+    async fn exists_by_email(&self, email: &str) -> Result<bool, ApiError>;
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, ApiError>;
+
+    // set last_login_at to now, answering with the updated user, this does not count as a change for updated_at
+    async fn record_login(&self, id: Uuid) -> Result<Option<User>, ApiError>;
+}
+
+// password hashes, kept apart from the users so they never end up in a response by accident
+#[async_trait]
+pub trait CredentialRepository: Send + Sync
+{
+    // None if the user has not set a password (or does not exist)
+    async fn password_hash(&self, user_id: Uuid) -> Result<Option<String>, ApiError>;
+
+    // false if the user does not exist, audit is None when the same password is only hashed again
+    async fn set_password_hash(&self, user_id: Uuid, hash: &str, audit: Option<&Audit>) -> Result<bool, ApiError>;
+}
+
+// a refresh token as stored, only its hash is ever kept
+pub struct NewRefreshToken
+{
+    pub hash: String,
+    pub user_id: Uuid,
+
+    // every token obtained by rotating the same login
+    pub family: Uuid,
+
+    pub expires_at: SystemTime,
+}
+
+pub enum Rotation
+{
+    // carries the id of the user the token belongs to
+    Rotated(Uuid),
+
+    // the token had already been rotated, its family is now revoked
+    Reused,
+
+    // unknown, expired or revoked
+    Invalid,
+}
+
+#[async_trait]
+pub trait TokenRepository: Send + Sync
+{
+    async fn create_refresh_token(&self, token: NewRefreshToken) -> Result<(), ApiError>;
+
+    // exchange a refresh token for its replacement in the same family, atomically
+    async fn rotate_refresh_token(&self, hash: &str, replacement: &str, expires_at: SystemTime) -> Result<Rotation, ApiError>;
+
+    // revoke the family of the token, unknown tokens are ignored
+    async fn revoke_family(&self, hash: &str) -> Result<(), ApiError>;
+}
+
+// a verification token as stored, only its hash is ever kept
+pub struct NewVerification
+{
+    pub hash: String,
+    pub user_id: Uuid,
+    pub expires_at: SystemTime,
+}
+
+// how often a user can be sent a token, or an address can ask for one
+#[derive(Clone, Copy)]
+pub struct SendLimit
+{
+    // between two tokens
+    pub interval: Duration,
+
+    // tokens within WINDOW
+    pub hourly: u32,
+}
+
+impl SendLimit
+{
+    pub const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+    // how long until another token can be sent given when the earlier ones were, None if one can be sent now
+    pub fn wait(&self, sent: &[SystemTime], now: SystemTime) -> Option<Duration>
+    {
+        let mut recent: Vec<SystemTime> = sent.iter().copied().filter(|at| *at + SendLimit::WINDOW > now).collect();
+        recent.sort();
+
+        let mut until = sent.iter().max().map(|latest| *latest + self.interval);
+
+        // the oldest tokens of the window have to leave it first
+        if recent.len() >= self.hourly as usize
+        {
+            let leaving = recent[recent.len() - self.hourly as usize] + SendLimit::WINDOW;
+
+            until = until.max(Some(leaving));
+        }
+
+        until.and_then(|until| until.duration_since(now).ok()).filter(|wait| !wait.is_zero())
+    }
+
+    // tokens older than this no longer count against the limit
+    pub fn retention(&self) -> Duration
+    {
+        SendLimit::WINDOW.max(self.interval)
+    }
+}
+
+pub enum Issue
+{
+    // carries the user, the token is for their current email
+    Issued(User),
+
+    AlreadyVerified,
+
+    // carries how long until another token can be sent
+    Throttled(Duration),
+
+    NotFound,
+}
+
+// tokens are bound to the email they were sent to, so one cannot verify an address the user changed to afterwards
+#[async_trait]
+pub trait VerificationRepository: Send + Sync
+{
+    // store a token for the current email of the user, unless it is verified already or the limit has been reached,
+    // forgetting their tokens that have expired and no longer count against the limit
+    async fn create_verification(&self, token: NewVerification, limit: SendLimit) -> Result<Issue, ApiError>;
+
+    // mark the email of the user verified and forget all their tokens, None if the token is unknown, expired,
+    // someone else's or for an earlier email
+    async fn verify_email(&self, user_id: Uuid, hash: &str, audit: &Audit) -> Result<Option<User>, ApiError>;
+}
+
+// a password reset token as stored, only its hash is ever kept
+pub struct NewPasswordReset
+{
+    pub hash: String,
+
+    // the token is for the user who has this email, if anyone does
+    pub email: String,
+
+    pub source_ip: IpAddr,
+    pub expires_at: SystemTime,
+}
+
+pub enum ResetRequest
+{
+    // carries the user, the token is for their current email
+    Issued(User),
+
+    // nobody has the email or they were sent a token too recently, which the caller must not learn
+    Ignored,
+
+    // the address asked too often, carries how long until it can ask again
+    Throttled(Duration),
+}
+
+// tokens are bound to the email they were sent to like verification tokens
+#[async_trait]
+pub trait PasswordResetRepository: Send + Sync
+{
+    // count the request against the address and, unless it is over its limit, store a token for the user with the email
+    // unless they are over theirs, forgetting their tokens that have expired and no longer count
+    async fn create_password_reset(&self, token: NewPasswordReset, user_limit: SendLimit, ip_limit: SendLimit) -> Result<ResetRequest, ApiError>;
+
+    // replace the password of the user the token was sent to, forget all their reset tokens and revoke their refresh tokens,
+    // false if the token is unknown, expired or for an earlier email
+    async fn reset_password(&self, hash: &str, password_hash: &str, audit: &Audit) -> Result<bool, ApiError>;
+}
+
+#[async_trait]
+pub trait RoleRepository: Send + Sync
+{
+    async fn list_roles(&self) -> Result<Vec<RoleInfo>, ApiError>;
+
+    // false if the user does not exist, RoleNotFound if the role does not, assigning twice is fine
+    async fn assign_role(&self, user_id: Uuid, role: &str, audit: &Audit) -> Result<bool, ApiError>;
+
+    // false if the user does not exist, RoleNotFound if the role does not, revoking twice is fine
+    async fn revoke_role(&self, user_id: Uuid, role: &str, audit: &Audit) -> Result<bool, ApiError>;
+
+    // every permission granted by any of the roles, unknown roles grant nothing
+    async fn permissions(&self, roles: &[String]) -> Result<Vec<String>, ApiError>;
+}
+
+pub struct NewBan
+{
+    pub user_id: Uuid,
+    pub moderator_id: Option<Uuid>,
+    pub reason: String,
+    pub expires_at: Option<SystemTime>,
+}
+
+pub enum Lift
+{
+    Lifted,
+
+    // already lifted or expired
+    Inactive,
+
+    // the user has no ban with this id
+    NotFound,
+}
+
+// a user is banned while they have an active ban, there is no flag to keep in sync
+#[async_trait]
+pub trait BanRepository: Send + Sync
+{
+    // None if the user does not exist
+    async fn create_ban(&self, ban: NewBan, audit: &Audit) -> Result<Option<Ban>, ApiError>;
+
+    // newest first, None if the user does not exist
+    async fn bans(&self, user_id: Uuid) -> Result<Option<Vec<Ban>>, ApiError>;
+
+    // lifted_by is None for API keys
+    async fn lift_ban(&self, user_id: Uuid, ban_id: Uuid, lifted_by: Option<Uuid>, audit: &Audit) -> Result<Lift, ApiError>;
+
+    // mark every ban that has expired but is not lifted yet as lifted when it expired, returning them
+    async fn lift_expired(&self, audit: &Audit) -> Result<Vec<Ban>, ApiError>;
+}
+
+// the entries are written by the other repositories, this only reads them
+#[async_trait]
+pub trait AuditRepository: Send + Sync
+{
+    // newest first
+    async fn audit_log(&self, query: &AuditQuery) -> Result<AuditPage, ApiError>;
+}
+
+// the events are written by the other repositories, in the same transaction as the change they announce
+#[async_trait]
+pub trait OutboxRepository: Send + Sync
+{
+    // up to limit of the oldest events that are due and wait behind no other event of their user, each with its failed
+    // attempts so far and pushed back by the lease so no one else sends it meanwhile
+    async fn claim_events(&self, limit: usize, lease: Duration) -> Result<Vec<(OutboxEvent, i32)>, ApiError>;
+
+    // the sink took the event, it leaves the outbox
+    async fn delivered(&self, id: i64) -> Result<(), ApiError>;
+
+    // the sink refused the event, it is sent again at retry_at and the later events of its user wait behind it until then
+    async fn failed(&self, id: i64, error: &str, retry_at: SystemTime) -> Result<(), ApiError>;
+}
+
+// deliveries are queued by publishing an event, in the same transaction, for every webhook subscribed to it
+#[async_trait]
+pub trait WebhookRepository: Send + Sync
+{
+    async fn create_webhook(&self, webhook: NewWebhook) -> Result<Webhook, ApiError>;
+
+    // oldest first
+    async fn webhooks(&self) -> Result<Vec<Webhook>, ApiError>;
+
+    async fn webhook(&self, id: Uuid) -> Result<Option<Webhook>, ApiError>;
+
+    // false if the webhook does not exist
+    async fn delete_webhook(&self, id: Uuid) -> Result<bool, ApiError>;
+
+    // newest first, None if the webhook does not exist
+    async fn deliveries(&self, webhook_id: Uuid, query: &DeliveryQuery) -> Result<Option<DeliveryPage>, ApiError>;
+
+    // queue a ping already claimed for the lease, None if the webhook does not exist
+    async fn create_ping(&self, webhook_id: Uuid, payload: serde_json::Value, lease: Duration) -> Result<Option<(Webhook, Delivery)>, ApiError>;
+
+    // up to limit of the pending deliveries that are due, oldest first, each pushed back by the lease so no one else sends it meanwhile
+    async fn claim_deliveries(&self, limit: usize, lease: Duration) -> Result<Vec<(Webhook, Delivery)>, ApiError>;
+
+    // None if the delivery is gone along with its webhook
+    async fn record_attempt(&self, id: i64, attempt: &Attempt) -> Result<Option<Delivery>, ApiError>;
+}
@@ -0,0 +1,1307 @@
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Row, Transaction};
+use uuid::Uuid;
+
+use crate::audit::{self, Action, Actor, Audit, AuditPage, Entry};
+use crate::bans::Ban;
+use crate::db::Pool;
+use crate::error::ApiError;
+use crate::outbox::{Kind, OutboxEvent};
+use crate::pagination::{AuditQuery, DeliveryQuery, ListQuery, SortField, SortKey};
+use crate::roles::{Permission, Role, RoleInfo};
+use crate::users::User;
+use crate::webhooks::{Attempt, Delivery, DeliveryPage, DeliveryStatus, NewWebhook, Outcome, Webhook};
+
+use super::{
+    AuditRepository, BanRepository, Change, CredentialRepository, Issue, Lift, NewBan, NewPasswordReset, NewRefreshToken, NewUser, NewVerification, OutboxRepository, Page,
+    PasswordResetRepository, ResetRequest, Restore, RoleRepository, Rotation, SendLimit, TokenRepository, UserRepository, VerificationRepository, WebhookRepository,
+};
+
+// arbitrary key for pg_advisory_xact_lock, replicas claim outbox events one after the other
+const RELAY_LOCK_KEY: i64 = 0x7573_6572_735f_6f62;
+
+// arbitrary first key for pg_advisory_xact_lock, the second is a hash of the address so its requests are counted one at a time
+const RESET_LOCK_CLASS: i32 = 0x7573_7072;
+
+// whether the user has a ban that is neither lifted nor expired, usable wherever the users table is in scope
+macro_rules! banned
+{
+    () => { "EXISTS (SELECT 1 FROM bans WHERE bans.user_id = users.id AND bans.lifted_at IS NULL AND (bans.expires_at IS NULL OR bans.expires_at > now()))" };
+}
+
+// a user with their roles and banned flag, usable wherever the users table is in scope
+const USER_COLUMNS: &str = concat!(
+    "id, name, email, email_verified, created_at, updated_at, last_login_at, deleted_at, ",
+    banned!(), " AS banned, ARRAY(SELECT role FROM user_roles WHERE user_id = users.id ORDER BY role) AS roles",
+);
+
+const AUDIT_COLUMNS: &str = "id, actor_type, actor_id, actor_key, action, user_id, changes, request_id, source_ip, created_at";
+
+const WEBHOOK_COLUMNS: &str = "id, url, events, secret, created_at";
+
+// next_attempt_at only matters while the delivery is pending
+const DELIVERY_COLUMNS: &str = "id, webhook_id, event_id, event, payload, status, attempts, CASE WHEN status = 'pending' THEN next_attempt_at END AS next_attempt_at, response_status, last_error, created_at, delivered_at";
+
+const BAN_COLUMNS: &str = "id, user_id, moderator_id, reason, created_at, expires_at, lifted_at, lifted_by, lifted_at IS NULL AND (expires_at IS NULL OR expires_at > now()) AS active";
+
+pub struct PostgresUserRepository
+{
+    pool: Pool,
+}
+
+impl PostgresUserRepository
+{
+    pub fn new(pool: Pool) -> Self
+    {
+        PostgresUserRepository { pool }
+    }
+}
+
+fn user_from_row(row: &Row) -> User
+{
+    User
+    {
+        id: Some(row.get("id")),
+        name: row.get("name"),
+        email: row.get("email"),
+        email_verified: Some(row.get("email_verified")),
+        roles: Some(row.get("roles")),
+        banned: row.get("banned"),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+        last_login_at: row.get("last_login_at"),
+        deleted_at: row.get("deleted_at"),
+    }
+}
+
+fn ban_from_row(row: &Row) -> Ban
+{
+    Ban
+    {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        moderator_id: row.get("moderator_id"),
+        reason: row.get("reason"),
+        created_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
+        lifted_at: row.get("lifted_at"),
+        lifted_by: row.get("lifted_by"),
+        active: row.get("active"),
+    }
+}
+
+fn webhook_from_row(row: &Row) -> Webhook
+{
+    Webhook
+    {
+        id: row.get("id"),
+        url: row.get("url"),
+        events: row.get("events"),
+        secret: row.get("secret"),
+        created_at: row.get("created_at"),
+    }
+}
+
+fn delivery_from_row(row: &Row) -> Delivery
+{
+    Delivery
+    {
+        id: row.get("id"),
+        webhook_id: row.get("webhook_id"),
+        event_id: row.get("event_id"),
+        event: row.get("event"),
+        payload: row.get("payload"),
+        status: DeliveryStatus::from_name(row.get("status")).expect("the table only accepts known statuses"),
+        attempts: row.get("attempts"),
+        next_attempt_at: row.get("next_attempt_at"),
+        response_status: row.get("response_status"),
+        last_error: row.get("last_error"),
+        created_at: row.get("created_at"),
+        delivered_at: row.get("delivered_at"),
+    }
+}
+
+fn entry_from_row(row: &Row) -> Entry
+{
+    let actor = match row.get("actor_type")
+    {
+        "user" => Actor::User { id: row.get("actor_id") },
+        "api_key" => Actor::ApiKey { name: row.get("actor_key") },
+        "anonymous" => Actor::Anonymous,
+        _ => Actor::System,
+    };
+
+    Entry
+    {
+        id: row.get("id"),
+        actor,
+        action: Action::from_name(row.get("action")).expect("the table only accepts known actions"),
+        user_id: row.get("user_id"),
+        changes: row.get("changes"),
+        request_id: row.get("request_id"),
+        source_ip: row.get("source_ip"),
+        created_at: row.get("created_at"),
+    }
+}
+
+// append an entry to the audit log, in the transaction of the change it records
+async fn record(transaction: &Transaction<'_>, audit: &Audit, action: Action, user_id: Uuid, changes: serde_json::Map<String, serde_json::Value>) -> Result<(), ApiError>
+{
+    let (actor_type, actor_id, actor_key) = match &audit.actor
+    {
+        Actor::User { id } => ("user", Some(*id), None),
+        Actor::ApiKey { name } => ("api_key", None, Some(name.as_str())),
+        Actor::Anonymous => ("anonymous", None, None),
+        Actor::System => ("system", None, None),
+    };
+
+    transaction.execute(
+        "INSERT INTO audit_log (actor_type, actor_id, actor_key, action, user_id, changes, request_id, source_ip) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        &[&actor_type, &actor_id, &actor_key, &action.name(), &user_id, &serde_json::Value::Object(changes), &audit.request_id, &audit.source_ip],
+    ).await?;
+
+    Ok(())
+}
+
+// add an event to the outbox and queue it for the webhooks subscribed to it, in the transaction of the change it announces
+async fn publish<T: Serialize>(transaction: &Transaction<'_>, kind: Kind, user_id: Uuid, data: &T) -> Result<(), ApiError>
+{
+    let data = serde_json::to_value(data).unwrap();
+
+    let row = transaction.query_one(
+        "INSERT INTO outbox (type, user_id, data) VALUES ($1, $2, $3) RETURNING id, occurred_at",
+        &[&kind.name(), &user_id, &data],
+    ).await?;
+
+    let event = OutboxEvent { id: row.get("id"), kind, user_id, data, occurred_at: row.get("occurred_at") };
+
+    transaction.execute(
+        "INSERT INTO webhook_deliveries (webhook_id, event_id, event, payload) SELECT id, $1, $2, $3 FROM webhooks WHERE $2 = ANY(events)",
+        &[&event.id, &kind.topic(), &serde_json::to_value(&event).unwrap()],
+    ).await?;
+
+    Ok(())
+}
+
+// a user that is not deleted, locked until the end of the transaction so the audit log and the outbox see every change in order
+async fn locked_user(transaction: &Transaction<'_>, id: Uuid) -> Result<Option<User>, ApiError>
+{
+    let row = transaction.query_opt(&format!("SELECT {} FROM users WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", USER_COLUMNS), &[&id]).await?;
+
+    Ok(row.as_ref().map(user_from_row))
+}
+
+async fn reload_user(transaction: &Transaction<'_>, id: Uuid) -> Result<User, ApiError>
+{
+    let row = transaction.query_one(&format!("SELECT {} FROM users WHERE id = $1", USER_COLUMNS), &[&id]).await?;
+
+    Ok(user_from_row(&row))
+}
+
+#[async_trait]
+impl UserRepository for PostgresUserRepository
+{
+    async fn get(&self, id: Uuid) -> Result<Option<User>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let row = client.query_opt(&format!("SELECT {} FROM users WHERE id = $1 AND deleted_at IS NULL", USER_COLUMNS), &[&id]).await?;
+
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    async fn get_including_deleted(&self, id: Uuid) -> Result<Option<User>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let row = client.query_opt(&format!("SELECT {} FROM users WHERE id = $1", USER_COLUMNS), &[&id]).await?;
+
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    async fn list(&self, query: &ListQuery) -> Result<Page, ApiError>
+    {
+        let (sql, params) = list_sql(query)?;
+        let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|param| param.as_ref() as &(dyn ToSql + Sync)).collect();
+
+        let client = self.pool.get().await?;
+
+        let mut rows = client.query(&sql, &params).await?;
+
+        // the extra row only tells us there is another page
+        let next = match rows.len() > query.limit as usize
+        {
+            true =>
+            {
+                rows.truncate(query.limit as usize);
+                rows.last().map(|last| query.next_link(last.get("id"), sort_key(last, query.sort)))
+            }
+            false => None,
+        };
+
+        Ok(Page { users: rows.iter().map(user_from_row).collect(), next })
+    }
+
+    async fn create(&self, user: NewUser, audit: &Audit) -> Result<User, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        // a concurrent insert with the same email still ends up as DuplicateEmail through the unique constraint,
+        // the user and their role are written by one statement so there is never a user without it
+        let row = transaction.query_one(
+            "WITH created AS (INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id, name, email, email_verified, created_at, updated_at, last_login_at, deleted_at),
+            granted AS (INSERT INTO user_roles (user_id, role) SELECT id, $3 FROM created)
+            SELECT *, FALSE AS banned, ARRAY[$3] AS roles FROM created",
+            &[&user.name, &user.email, &Role::DEFAULT.name()],
+        ).await?;
+
+        let created = user_from_row(&row);
+
+        record(&transaction, audit, Action::Created, created.id.unwrap_or_default(), audit::diff(None, Some(&created))).await?;
+        publish(&transaction, Kind::Created, created.id.unwrap_or_default(), &created).await?;
+
+        transaction.commit().await?;
+
+        Ok(created)
+    }
+
+    async fn update(&self, id: Uuid, change: Change<'_>, audit: &Audit) -> Result<Option<User>, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+
+        // lock the row so the change is computed from the version that gets updated
+        let transaction = client.transaction().await?;
+
+        let current = match locked_user(&transaction, id).await?
+        {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        let changes = change(&current)?;
+
+        // nothing changed, there is no need to touch the row
+        if changes.is_empty()
+        {
+            return Ok(Some(current));
+        }
+
+        let mut columns = Vec::new();
+        let mut values: Vec<&(dyn ToSql + Sync)> = Vec::new();
+
+        if let Some(name) = &changes.name
+        {
+            columns.push("name");
+            values.push(name);
+        }
+
+        if let Some(email) = &changes.email
+        {
+            columns.push("email");
+            values.push(email);
+        }
+
+        let mut assignments: Vec<String> = columns.iter().enumerate().map(|(i, column)| format!("{}=${}", column, i + 1)).collect();
+
+        // a new email has to be verified again, the same one written again keeps its flag
+        if let Some(i) = columns.iter().position(|column| *column == "email")
+        {
+            assignments.push(format!("email_verified = email_verified AND email = ${}", i + 1));
+        }
+
+        let query = format!("UPDATE users SET {} WHERE id=${} RETURNING {}", assignments.join(", "), values.len() + 1, USER_COLUMNS);
+
+        values.push(&id);
+
+        let row = transaction.query_one(&query, &values).await?;
+        let updated = user_from_row(&row);
+
+        let changes = audit::diff(Some(&current), Some(&updated));
+
+        if !changes.is_empty()
+        {
+            record(&transaction, audit, Action::Updated, id, changes).await?;
+            publish(&transaction, Kind::Updated, id, &updated).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(Some(updated))
+    }
+
+    async fn delete(&self, id: Uuid, audit: &Audit) -> Result<bool, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let row = transaction.query_opt(&format!("UPDATE users SET deleted_at = now() WHERE id = $1 AND deleted_at IS NULL RETURNING {}", USER_COLUMNS), &[&id]).await?;
+
+        let deleted = match row
+        {
+            Some(row) => user_from_row(&row),
+            None => return Ok(false),
+        };
+
+        // the user keeps their rows until the purge, only their sessions end right away
+        transaction.execute("UPDATE refresh_tokens SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL", &[&id]).await?;
+
+        let before = User { deleted_at: None, ..deleted.clone() };
+
+        record(&transaction, audit, Action::Deleted, id, audit::diff(Some(&before), Some(&deleted))).await?;
+        publish(&transaction, Kind::Deleted, id, &deleted).await?;
+
+        transaction.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn restore(&self, id: Uuid, deleted_after: SystemTime, audit: &Audit) -> Result<Restore, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let row = transaction.query_opt("SELECT deleted_at FROM users WHERE id = $1 FOR UPDATE", &[&id]).await?;
+
+        let deleted_at: SystemTime = match row.map(|row| row.get::<_, Option<SystemTime>>(0))
+        {
+            Some(Some(deleted_at)) if deleted_at > deleted_after => deleted_at,
+            Some(Some(_)) => return Ok(Restore::Expired),
+            Some(None) => return Ok(Restore::NotDeleted),
+            None => return Ok(Restore::NotFound),
+        };
+
+        let row = transaction.query_one(&format!("UPDATE users SET deleted_at = NULL WHERE id = $1 RETURNING {}", USER_COLUMNS), &[&id]).await?;
+        let restored = user_from_row(&row);
+
+        let before = User { deleted_at: Some(deleted_at), ..restored.clone() };
+
+        record(&transaction, audit, Action::Restored, id, audit::diff(Some(&before), Some(&restored))).await?;
+        publish(&transaction, Kind::Updated, id, &restored).await?;
+
+        transaction.commit().await?;
+
+        Ok(Restore::Restored(restored))
+    }
+
+    async fn purge(&self, deleted_before: SystemTime, audit: &Audit) -> Result<Vec<Uuid>, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        // credentials, tokens, roles and bans go with the user through their foreign keys
+        let rows = transaction.query("DELETE FROM users WHERE deleted_at <= $1 RETURNING id", &[&deleted_before]).await?;
+        let purged: Vec<Uuid> = rows.iter().map(|row| row.get(0)).collect();
+
+        // what the user looked like is gone too, the entry only says that they were purged
+        for id in &purged
+        {
+            record(&transaction, audit, Action::Purged, *id, serde_json::Map::new()).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(purged)
+    }
+
+    async fn exists_by_email(&self, email: &str) -> Result<bool, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let row = client.query_one("SELECT EXISTS(SELECT 1 FROM users WHERE email=$1)", &[&email]).await?;
+
+        Ok(row.get(0))
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let row = client.query_opt(&format!("SELECT {} FROM users WHERE email = $1 AND deleted_at IS NULL", USER_COLUMNS), &[&email]).await?;
+
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    async fn record_login(&self, id: Uuid) -> Result<Option<User>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let row = client.query_opt(&format!("UPDATE users SET last_login_at = now() WHERE id = $1 AND deleted_at IS NULL RETURNING {}", USER_COLUMNS), &[&id]).await?;
+
+        Ok(row.as_ref().map(user_from_row))
+    }
+}
+
+#[async_trait]
+impl CredentialRepository for PostgresUserRepository
+{
+    async fn password_hash(&self, user_id: Uuid) -> Result<Option<String>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let row = client.query_opt("SELECT password_hash FROM credentials WHERE user_id = $1", &[&user_id]).await?;
+
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    async fn set_password_hash(&self, user_id: Uuid, hash: &str, audit: Option<&Audit>) -> Result<bool, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        // selecting from users instead of relying on the foreign key keeps a missing user from being an error
+        let written = transaction.execute(
+            "INSERT INTO credentials (user_id, password_hash) SELECT id, $2 FROM users WHERE id = $1 AND deleted_at IS NULL
+            ON CONFLICT (user_id) DO UPDATE SET password_hash = EXCLUDED.password_hash, updated_at = now()",
+            &[&user_id, &hash],
+        ).await?;
+
+        // the hash is never part of the entry
+        if let Some(audit) = audit.filter(|_| written > 0)
+        {
+            record(&transaction, audit, Action::PasswordChanged, user_id, serde_json::Map::new()).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(written > 0)
+    }
+}
+
+#[async_trait]
+impl TokenRepository for PostgresUserRepository
+{
+    async fn create_refresh_token(&self, token: NewRefreshToken) -> Result<(), ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        client.execute(
+            "INSERT INTO refresh_tokens (token_hash, family_id, user_id, expires_at) VALUES ($1, $2, $3, $4)",
+            &[&token.hash, &token.family, &token.user_id, &token.expires_at],
+        ).await?;
+
+        Ok(())
+    }
+
+    async fn rotate_refresh_token(&self, hash: &str, replacement: &str, expires_at: SystemTime) -> Result<Rotation, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+
+        // lock the token so two concurrent refreshes cannot both rotate it
+        let transaction = client.transaction().await?;
+
+        let row = match transaction.query_opt(
+            "SELECT user_id, family_id, used_at IS NOT NULL AS used, revoked_at IS NOT NULL OR expires_at <= now() AS dead FROM refresh_tokens WHERE token_hash = $1 FOR UPDATE",
+            &[&hash],
+        ).await?
+        {
+            Some(row) => row,
+            None => return Ok(Rotation::Invalid),
+        };
+
+        let user_id: Uuid = row.get("user_id");
+        let family: Uuid = row.get("family_id");
+
+        if row.get("dead")
+        {
+            return Ok(Rotation::Invalid);
+        }
+
+        // someone kept a copy of a token that was already exchanged, nobody in the family can be trusted anymore
+        if row.get("used")
+        {
+            transaction.execute("UPDATE refresh_tokens SET revoked_at = now() WHERE family_id = $1 AND revoked_at IS NULL", &[&family]).await?;
+            transaction.commit().await?;
+
+            return Ok(Rotation::Reused);
+        }
+
+        transaction.execute("UPDATE refresh_tokens SET used_at = now() WHERE token_hash = $1", &[&hash]).await?;
+        transaction.execute(
+            "INSERT INTO refresh_tokens (token_hash, family_id, user_id, expires_at) VALUES ($1, $2, $3, $4)",
+            &[&replacement, &family, &user_id, &expires_at],
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok(Rotation::Rotated(user_id))
+    }
+
+    async fn revoke_family(&self, hash: &str) -> Result<(), ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        client.execute(
+            "UPDATE refresh_tokens SET revoked_at = now() WHERE revoked_at IS NULL AND family_id = (SELECT family_id FROM refresh_tokens WHERE token_hash = $1)",
+            &[&hash],
+        ).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl VerificationRepository for PostgresUserRepository
+{
+    async fn create_verification(&self, token: NewVerification, limit: SendLimit) -> Result<Issue, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+
+        // the user stays locked so concurrent requests see each other's tokens when counting
+        let transaction = client.transaction().await?;
+
+        let user = match locked_user(&transaction, token.user_id).await?
+        {
+            Some(user) => user,
+            None => return Ok(Issue::NotFound),
+        };
+
+        if user.email_verified == Some(true)
+        {
+            return Ok(Issue::AlreadyVerified);
+        }
+
+        transaction.execute(
+            "DELETE FROM email_verifications WHERE user_id = $1 AND expires_at <= now() AND created_at <= now() - make_interval(secs => $2)",
+            &[&token.user_id, &limit.retention().as_secs_f64()],
+        ).await?;
+
+        let row = transaction.query_one("SELECT now(), ARRAY(SELECT created_at FROM email_verifications WHERE user_id = $1)", &[&token.user_id]).await?;
+
+        if let Some(wait) = limit.wait(&row.get::<_, Vec<SystemTime>>(1), row.get(0))
+        {
+            return Ok(Issue::Throttled(wait));
+        }
+
+        transaction.execute(
+            "INSERT INTO email_verifications (token_hash, user_id, email, expires_at) VALUES ($1, $2, $3, $4)",
+            &[&token.hash, &token.user_id, &user.email, &token.expires_at],
+        ).await?;
+
+        transaction.commit().await?;
+
+        Ok(Issue::Issued(user))
+    }
+
+    async fn verify_email(&self, user_id: Uuid, hash: &str, audit: &Audit) -> Result<Option<User>, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let current = match locked_user(&transaction, user_id).await?
+        {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        let valid = transaction.query_opt(
+            "SELECT 1 FROM email_verifications WHERE token_hash = $1 AND user_id = $2 AND email = $3 AND expires_at > now()",
+            &[&hash, &user_id, &current.email],
+        ).await?;
+
+        if valid.is_none()
+        {
+            return Ok(None);
+        }
+
+        let row = transaction.query_one(&format!("UPDATE users SET email_verified = TRUE WHERE id = $1 RETURNING {}", USER_COLUMNS), &[&user_id]).await?;
+        let verified = user_from_row(&row);
+
+        // the other tokens could only verify the same email again
+        transaction.execute("DELETE FROM email_verifications WHERE user_id = $1", &[&user_id]).await?;
+
+        let changes = audit::diff(Some(&current), Some(&verified));
+
+        if !changes.is_empty()
+        {
+            record(&transaction, audit, Action::EmailVerified, user_id, changes).await?;
+            publish(&transaction, Kind::Updated, user_id, &verified).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(Some(verified))
+    }
+}
+
+#[async_trait]
+impl PasswordResetRepository for PostgresUserRepository
+{
+    async fn create_password_reset(&self, token: NewPasswordReset, user_limit: SendLimit, ip_limit: SendLimit) -> Result<ResetRequest, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        transaction.execute("SELECT pg_advisory_xact_lock($1, hashtext($2))", &[&RESET_LOCK_CLASS, &token.source_ip.to_string()]).await?;
+
+        // requests of every address that no longer count
+        transaction.execute("DELETE FROM password_reset_requests WHERE created_at <= now() - make_interval(secs => $1)", &[&ip_limit.retention().as_secs_f64()]).await?;
+
+        let row = transaction.query_one("SELECT now(), ARRAY(SELECT created_at FROM password_reset_requests WHERE source_ip = $1)", &[&token.source_ip]).await?;
+
+        if let Some(wait) = ip_limit.wait(&row.get::<_, Vec<SystemTime>>(1), row.get(0))
+        {
+            return Ok(ResetRequest::Throttled(wait));
+        }
+
+        transaction.execute("INSERT INTO password_reset_requests (source_ip) VALUES ($1)", &[&token.source_ip]).await?;
+
+        // from here on the request counts against the address whatever the outcome
+        let issued = match transaction.query_opt(&format!("SELECT {} FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE", USER_COLUMNS), &[&token.email]).await?
+        {
+            Some(row) =>
+            {
+                let user = user_from_row(&row);
+                let user_id = user.id.unwrap_or_default();
+
+                transaction.execute(
+                    "DELETE FROM password_resets WHERE user_id = $1 AND expires_at <= now() AND created_at <= now() - make_interval(secs => $2)",
+                    &[&user_id, &user_limit.retention().as_secs_f64()],
+                ).await?;
+
+                let row = transaction.query_one("SELECT now(), ARRAY(SELECT created_at FROM password_resets WHERE user_id = $1)", &[&user_id]).await?;
+
+                match user_limit.wait(&row.get::<_, Vec<SystemTime>>(1), row.get(0))
+                {
+                    Some(_) => None,
+                    None =>
+                    {
+                        transaction.execute(
+                            "INSERT INTO password_resets (token_hash, user_id, email, expires_at) VALUES ($1, $2, $3, $4)",
+                            &[&token.hash, &user_id, &user.email, &token.expires_at],
+                        ).await?;
+
+                        Some(user)
+                    }
+                }
+            }
+            None => None,
+        };
+
+        transaction.commit().await?;
+
+        Ok(issued.map_or(ResetRequest::Ignored, ResetRequest::Issued))
+    }
+
+    async fn reset_password(&self, hash: &str, password_hash: &str, audit: &Audit) -> Result<bool, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let user_id: Uuid = match transaction.query_opt("SELECT user_id FROM password_resets WHERE token_hash = $1", &[&hash]).await?
+        {
+            Some(row) => row.get(0),
+            None => return Ok(false),
+        };
+
+        // the email cannot change while the token is checked against it
+        let user = match locked_user(&transaction, user_id).await?
+        {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+
+        // the token has to be unexpired and for the current email
+        let valid = transaction.execute(
+            "DELETE FROM password_resets WHERE token_hash = $1 AND email = $2 AND expires_at > now()",
+            &[&hash, &user.email],
+        ).await?;
+
+        if valid == 0
+        {
+            return Ok(false);
+        }
+
+        transaction.execute(
+            "INSERT INTO credentials (user_id, password_hash) VALUES ($1, $2) ON CONFLICT (user_id) DO UPDATE SET password_hash = EXCLUDED.password_hash, updated_at = now()",
+            &[&user_id, &password_hash],
+        ).await?;
+
+        // whoever knew the old password is logged out everywhere, the other tokens could only do the same again
+        transaction.execute("DELETE FROM password_resets WHERE user_id = $1", &[&user_id]).await?;
+        transaction.execute("UPDATE refresh_tokens SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL", &[&user_id]).await?;
+
+        record(&transaction, audit, Action::PasswordChanged, user_id, serde_json::Map::new()).await?;
+
+        transaction.commit().await?;
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl RoleRepository for PostgresUserRepository
+{
+    async fn list_roles(&self) -> Result<Vec<RoleInfo>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let rows = client.query("SELECT name, description, permissions, built_in FROM roles ORDER BY name", &[]).await?;
+
+        Ok(rows.iter().map(|row| RoleInfo { name: row.get(0), description: row.get(1), permissions: row.get(2), built_in: row.get(3) }).collect())
+    }
+
+    async fn assign_role(&self, user_id: Uuid, role: &str, audit: &Audit) -> Result<bool, ApiError>
+    {
+        self.change_role(user_id, role, Action::RoleAssigned, audit).await
+    }
+
+    async fn revoke_role(&self, user_id: Uuid, role: &str, audit: &Audit) -> Result<bool, ApiError>
+    {
+        self.change_role(user_id, role, Action::RoleRevoked, audit).await
+    }
+
+    async fn permissions(&self, roles: &[String]) -> Result<Vec<String>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let rows = client.query("SELECT DISTINCT unnest(permissions) FROM roles WHERE name = ANY($1) ORDER BY 1", &[&roles]).await?;
+
+        Ok(rows.iter().map(|row| row.get::<_, String>(0)).filter(|name| Permission::from_name(name).is_some()).collect())
+    }
+}
+
+#[async_trait]
+impl BanRepository for PostgresUserRepository
+{
+    async fn create_ban(&self, ban: NewBan, audit: &Audit) -> Result<Option<Ban>, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let before = match locked_user(&transaction, ban.user_id).await?
+        {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        let row = transaction.query_one(
+            &format!("INSERT INTO bans (user_id, moderator_id, reason, expires_at) VALUES ($1, $2, $3, $4) RETURNING {}", BAN_COLUMNS),
+            &[&ban.user_id, &ban.moderator_id, &ban.reason, &ban.expires_at],
+        ).await?;
+
+        let created = ban_from_row(&row);
+        let after = reload_user(&transaction, ban.user_id).await?;
+
+        let mut changes = audit::diff(Some(&before), Some(&after));
+        changes.insert("ban".to_string(), audit::change(None, Some(&created)));
+
+        record(&transaction, audit, Action::Banned, ban.user_id, changes).await?;
+        publish(&transaction, Kind::Banned, ban.user_id, &created).await?;
+
+        transaction.commit().await?;
+
+        Ok(Some(created))
+    }
+
+    async fn bans(&self, user_id: Uuid) -> Result<Option<Vec<Ban>>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let rows = client.query(&format!("SELECT {} FROM bans WHERE user_id = $1 ORDER BY created_at DESC, id", BAN_COLUMNS), &[&user_id]).await?;
+
+        // the bans of a deleted user are kept for a restore, but hidden like the user
+        if client.query_opt("SELECT 1 FROM users WHERE id = $1 AND deleted_at IS NULL", &[&user_id]).await?.is_none()
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(rows.iter().map(ban_from_row).collect()))
+    }
+
+    async fn lift_ban(&self, user_id: Uuid, ban_id: Uuid, lifted_by: Option<Uuid>, audit: &Audit) -> Result<Lift, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        let before = match locked_user(&transaction, user_id).await?
+        {
+            Some(user) => user,
+            None => return Ok(Lift::NotFound),
+        };
+
+        let row = transaction.query_opt(
+            &format!("UPDATE bans SET lifted_at = now(), lifted_by = $3 WHERE id = $1 AND user_id = $2 AND lifted_at IS NULL AND (expires_at IS NULL OR expires_at > now()) RETURNING {}", BAN_COLUMNS),
+            &[&ban_id, &user_id, &lifted_by],
+        ).await?;
+
+        let lifted = match row
+        {
+            Some(row) => ban_from_row(&row),
+            None => return match transaction.query_opt("SELECT 1 FROM bans WHERE id = $1 AND user_id = $2", &[&ban_id, &user_id]).await?
+            {
+                Some(_) => Ok(Lift::Inactive),
+                None => Ok(Lift::NotFound),
+            },
+        };
+
+        let after = reload_user(&transaction, user_id).await?;
+
+        let mut changes = audit::diff(Some(&before), Some(&after));
+        changes.insert("ban".to_string(), audit::change(Some(&lifted.unlifted()), Some(&lifted)));
+
+        record(&transaction, audit, Action::Unbanned, user_id, changes).await?;
+        publish(&transaction, Kind::Unbanned, user_id, &lifted).await?;
+
+        transaction.commit().await?;
+
+        Ok(Lift::Lifted)
+    }
+
+    async fn lift_expired(&self, audit: &Audit) -> Result<Vec<Ban>, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        // the users are locked first, like everywhere else, so their events cannot overtake a concurrent change
+        let users = transaction.query(
+            "SELECT id FROM users WHERE id IN (SELECT user_id FROM bans WHERE lifted_at IS NULL AND expires_at <= now()) AND deleted_at IS NULL ORDER BY id FOR UPDATE",
+            &[],
+        ).await?;
+
+        // every replica runs this, the row locks make sure each ban is lifted (and announced) once
+        let rows = transaction.query(
+            &format!("UPDATE bans SET lifted_at = expires_at WHERE lifted_at IS NULL AND expires_at <= now() RETURNING {}", BAN_COLUMNS),
+            &[],
+        ).await?;
+
+        let lifted: Vec<Ban> = rows.iter().map(ban_from_row).collect();
+
+        // the users stopped being banned when the bans expired, so only the bans themselves change here
+        for ban in &lifted
+        {
+            let mut changes = serde_json::Map::new();
+            changes.insert("ban".to_string(), audit::change(Some(&ban.unlifted()), Some(ban)));
+
+            record(&transaction, audit, Action::Unbanned, ban.user_id, changes).await?;
+        }
+
+        // deleted users are gone for the consumers already
+        let live: Vec<Uuid> = users.iter().map(|row| row.get(0)).collect();
+
+        for ban in lifted.iter().filter(|ban| live.contains(&ban.user_id))
+        {
+            publish(&transaction, Kind::Unbanned, ban.user_id, ban).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(lifted)
+    }
+}
+
+#[async_trait]
+impl AuditRepository for PostgresUserRepository
+{
+    async fn audit_log(&self, query: &AuditQuery) -> Result<AuditPage, ApiError>
+    {
+        let (sql, params) = audit_sql(query);
+        let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|param| param.as_ref() as &(dyn ToSql + Sync)).collect();
+
+        let client = self.pool.get().await?;
+
+        let mut rows = client.query(&sql, &params).await?;
+
+        let next = match rows.len() > query.limit as usize
+        {
+            true =>
+            {
+                rows.truncate(query.limit as usize);
+                rows.last().map(|last| query.next_link(last.get("id")))
+            }
+            false => None,
+        };
+
+        Ok(AuditPage { entries: rows.iter().map(entry_from_row).collect(), next })
+    }
+}
+
+#[async_trait]
+impl OutboxRepository for PostgresUserRepository
+{
+    async fn claim_events(&self, limit: usize, lease: Duration) -> Result<Vec<(OutboxEvent, i32)>, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        // otherwise a replica could claim the later events of a user while another one is claiming the earlier ones
+        transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&RELAY_LOCK_KEY]).await?;
+
+        // an event claimed by someone else or waiting for a retry holds up the later events of its user, nobody else's;
+        // events committed later with a lower id are picked up next time, only the events of one user have to stay in order
+        // and those are written one transaction after the other because the user is locked
+        let rows = transaction.query(
+            "WITH due AS (SELECT id AS due_id FROM outbox WHERE next_attempt_at <= now() \
+                 AND NOT EXISTS (SELECT 1 FROM outbox earlier WHERE earlier.user_id = outbox.user_id AND earlier.id < outbox.id AND earlier.next_attempt_at > now()) \
+                 ORDER BY id LIMIT $1) \
+             UPDATE outbox SET next_attempt_at = now() + make_interval(secs => $2) FROM due WHERE id = due_id RETURNING id, type, user_id, data, occurred_at, attempts",
+            &[&(limit as i64), &lease.as_secs_f64()],
+        ).await?;
+
+        // the lease is only taken once the claim is committed, the sink is never waited for inside a transaction
+        transaction.commit().await?;
+
+        let mut claimed: Vec<(OutboxEvent, i32)> = rows.iter().map(|row|
+        {
+            let event = OutboxEvent
+            {
+                id: row.get("id"),
+                kind: Kind::from_name(row.get("type")).expect("the table only accepts known types"),
+                user_id: row.get("user_id"),
+                data: row.get("data"),
+                occurred_at: row.get("occurred_at"),
+            };
+
+            (event, row.get("attempts"))
+        }).collect();
+
+        claimed.sort_by_key(|(event, _)| event.id);
+
+        Ok(claimed)
+    }
+
+    async fn delivered(&self, id: i64) -> Result<(), ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        client.execute("DELETE FROM outbox WHERE id = $1", &[&id]).await?;
+
+        Ok(())
+    }
+
+    async fn failed(&self, id: i64, error: &str, retry_at: SystemTime) -> Result<(), ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        client.execute("UPDATE outbox SET attempts = attempts + 1, last_error = $2, next_attempt_at = $3 WHERE id = $1", &[&id, &error, &retry_at]).await?;
+
+        // the later events of the user claimed along with it are handed back, they wait behind it now
+        client.execute("UPDATE outbox SET next_attempt_at = now() WHERE user_id = (SELECT user_id FROM outbox WHERE id = $1) AND id > $1", &[&id]).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl WebhookRepository for PostgresUserRepository
+{
+    async fn create_webhook(&self, webhook: NewWebhook) -> Result<Webhook, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let row = client.query_one(
+            &format!("INSERT INTO webhooks (url, events, secret) VALUES ($1, $2, $3) RETURNING {}", WEBHOOK_COLUMNS),
+            &[&webhook.url, &webhook.events, &webhook.secret],
+        ).await?;
+
+        Ok(webhook_from_row(&row))
+    }
+
+    async fn webhooks(&self) -> Result<Vec<Webhook>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let rows = client.query(&format!("SELECT {} FROM webhooks ORDER BY created_at, id", WEBHOOK_COLUMNS), &[]).await?;
+
+        Ok(rows.iter().map(webhook_from_row).collect())
+    }
+
+    async fn webhook(&self, id: Uuid) -> Result<Option<Webhook>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let row = client.query_opt(&format!("SELECT {} FROM webhooks WHERE id = $1", WEBHOOK_COLUMNS), &[&id]).await?;
+
+        Ok(row.as_ref().map(webhook_from_row))
+    }
+
+    async fn delete_webhook(&self, id: Uuid) -> Result<bool, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        Ok(client.execute("DELETE FROM webhooks WHERE id = $1", &[&id]).await? > 0)
+    }
+
+    async fn deliveries(&self, webhook_id: Uuid, query: &DeliveryQuery) -> Result<Option<DeliveryPage>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        if client.query_opt("SELECT 1 FROM webhooks WHERE id = $1", &[&webhook_id]).await?.is_none()
+        {
+            return Ok(None);
+        }
+
+        // one more than asked for to know whether there is a next page
+        let mut rows = client.query(
+            &format!("SELECT {} FROM webhook_deliveries WHERE webhook_id = $1 AND ($2::BIGINT IS NULL OR id < $2) AND ($3::TEXT IS NULL OR status = $3) ORDER BY id DESC LIMIT $4", DELIVERY_COLUMNS),
+            &[&webhook_id, &query.after, &query.status.map(DeliveryStatus::name), &(query.limit + 1)],
+        ).await?;
+
+        let next = match rows.len() > query.limit as usize
+        {
+            true =>
+            {
+                rows.truncate(query.limit as usize);
+                rows.last().map(|last| query.next_link(last.get("id")))
+            }
+            false => None,
+        };
+
+        Ok(Some(DeliveryPage { deliveries: rows.iter().map(delivery_from_row).collect(), next }))
+    }
+
+    async fn create_ping(&self, webhook_id: Uuid, payload: serde_json::Value, lease: Duration) -> Result<Option<(Webhook, Delivery)>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        let webhook = match client.query_opt(&format!("SELECT {} FROM webhooks WHERE id = $1", WEBHOOK_COLUMNS), &[&webhook_id]).await?
+        {
+            Some(row) => webhook_from_row(&row),
+            None => return Ok(None),
+        };
+
+        // inserting from the webhook rather than its id finds nothing instead of failing when it was just deleted
+        let row = client.query_opt(
+            &format!("INSERT INTO webhook_deliveries (webhook_id, event, payload, next_attempt_at) SELECT id, 'ping', $2, now() + make_interval(secs => $3) FROM webhooks WHERE id = $1 RETURNING {}", DELIVERY_COLUMNS),
+            &[&webhook_id, &payload, &lease.as_secs_f64()],
+        ).await?;
+
+        Ok(row.map(|row| (webhook, delivery_from_row(&row))))
+    }
+
+    async fn claim_deliveries(&self, limit: usize, lease: Duration) -> Result<Vec<(Webhook, Delivery)>, ApiError>
+    {
+        let client = self.pool.get().await?;
+
+        // SKIP LOCKED lets replicas claim at the same time without waiting for each other or claiming the same delivery
+        let rows = client.query(
+            &format!(
+                "WITH due AS (SELECT id AS due_id FROM webhook_deliveries WHERE status = 'pending' AND next_attempt_at <= now() ORDER BY next_attempt_at, id LIMIT $1 FOR UPDATE SKIP LOCKED) \
+                 UPDATE webhook_deliveries SET next_attempt_at = now() + make_interval(secs => $2) FROM due WHERE id = due_id RETURNING {}",
+                DELIVERY_COLUMNS,
+            ),
+            &[&(limit as i64), &lease.as_secs_f64()],
+        ).await?;
+
+        let mut deliveries: Vec<Delivery> = rows.iter().map(delivery_from_row).collect();
+        deliveries.sort_by_key(|delivery| delivery.id);
+
+        let ids: Vec<Uuid> = deliveries.iter().map(|delivery| delivery.webhook_id).collect();
+        let rows = client.query(&format!("SELECT {} FROM webhooks WHERE id = ANY($1)", WEBHOOK_COLUMNS), &[&ids]).await?;
+        let webhooks: Vec<Webhook> = rows.iter().map(webhook_from_row).collect();
+
+        // a delivery whose webhook was deleted in the meantime went with it
+        Ok(deliveries.into_iter().filter_map(|delivery| webhooks.iter().find(|webhook| webhook.id == delivery.webhook_id).map(|webhook| (webhook.clone(), delivery))).collect())
+    }
+
+    async fn record_attempt(&self, id: i64, attempt: &Attempt) -> Result<Option<Delivery>, ApiError>
+    {
+        let (status, next_attempt_at) = match attempt.outcome
+        {
+            Outcome::Delivered => (DeliveryStatus::Delivered, None),
+            Outcome::Retry(at) => (DeliveryStatus::Pending, Some(at)),
+            Outcome::Dead => (DeliveryStatus::Dead, None),
+        };
+
+        let client = self.pool.get().await?;
+
+        let row = client.query_opt(
+            &format!(
+                "UPDATE webhook_deliveries SET status = $2, attempts = attempts + 1, next_attempt_at = COALESCE($3, next_attempt_at), response_status = $4, last_error = $5, \
+                 delivered_at = CASE WHEN $2 = 'delivered' THEN now() END WHERE id = $1 RETURNING {}",
+                DELIVERY_COLUMNS,
+            ),
+            &[&id, &status.name(), &next_attempt_at, &attempt.response_status, &attempt.error],
+        ).await?;
+
+        Ok(row.as_ref().map(delivery_from_row))
+    }
+}
+
+impl PostgresUserRepository
+{
+    // assign or revoke a role, false if the user does not exist, RoleNotFound if the role does not
+    async fn change_role(&self, user_id: Uuid, role: &str, action: Action, audit: &Audit) -> Result<bool, ApiError>
+    {
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+
+        if transaction.query_opt("SELECT 1 FROM roles WHERE name = $1", &[&role]).await?.is_none()
+        {
+            return Err(ApiError::RoleNotFound);
+        }
+
+        let before = match locked_user(&transaction, user_id).await?
+        {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+
+        let sql = match action
+        {
+            Action::RoleAssigned => "INSERT INTO user_roles (user_id, role) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            _ => "DELETE FROM user_roles WHERE user_id = $1 AND role = $2",
+        };
+
+        // assigning a role the user has, or revoking one they do not have, changes nothing
+        if transaction.execute(sql, &[&user_id, &role]).await? > 0
+        {
+            let after = reload_user(&transaction, user_id).await?;
+
+            record(&transaction, audit, action, user_id, audit::diff(Some(&before), Some(&after))).await?;
+            publish(&transaction, Kind::Updated, user_id, &after).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(true)
+    }
+}
+
+fn sort_key(row: &Row, sort: SortField) -> SortKey
+{
+    match sort
+    {
+        SortField::Created => SortKey::Time(row.get("created_at")),
+        _ => SortKey::Text(row.get(sort.column())),
+    }
+}
+
+// build the SELECT for a page of the audit log, newest first with one extra row to know whether another page follows
+fn audit_sql(query: &AuditQuery) -> (String, Vec<Box<dyn ToSql + Sync + Send>>)
+{
+    let mut conditions = Vec::new();
+    let mut params: Vec<Box<dyn ToSql + Sync + Send>> = Vec::new();
+
+    if let Some(user_id) = query.user_id
+    {
+        params.push(Box::new(user_id));
+        conditions.push(format!("user_id = ${}", params.len()));
+    }
+
+    if let Some(actor_id) = query.actor_id
+    {
+        params.push(Box::new(actor_id));
+        conditions.push(format!("actor_id = ${}", params.len()));
+    }
+
+    if let Some(action) = query.action
+    {
+        params.push(Box::new(action.name()));
+        conditions.push(format!("action = ${}", params.len()));
+    }
+
+    if let Some(request_id) = &query.request_id
+    {
+        params.push(Box::new(request_id.clone()));
+        conditions.push(format!("request_id = ${}", params.len()));
+    }
+
+    if let Some(time) = query.created_after
+    {
+        params.push(Box::new(time));
+        conditions.push(format!("created_at > ${}", params.len()));
+    }
+
+    if let Some(time) = query.created_before
+    {
+        params.push(Box::new(time));
+        conditions.push(format!("created_at < ${}", params.len()));
+    }
+
+    if let Some(after) = query.after
+    {
+        params.push(Box::new(after));
+        conditions.push(format!("id < ${}", params.len()));
+    }
+
+    let filter = match conditions.is_empty()
+    {
+        true => String::new(),
+        false => format!("WHERE {}", conditions.join(" AND ")),
+    };
+
+    params.push(Box::new(query.limit + 1));
+
+    let sql = format!("SELECT {} FROM audit_log {} ORDER BY id DESC LIMIT ${}", AUDIT_COLUMNS, filter, params.len());
+
+    (sql, params)
+}
+
+// build the SELECT for a page, one extra row is fetched to know whether another page follows
+fn list_sql(query: &ListQuery) -> Result<(String, Vec<Box<dyn ToSql + Sync + Send>>), ApiError>
+{
+    let mut conditions = Vec::new();
+    let mut params: Vec<Box<dyn ToSql + Sync + Send>> = Vec::new();
+
+    if !query.include_deleted
+    {
+        conditions.push("deleted_at IS NULL".to_string());
+    }
+
+    if let Some(role) = &query.role
+    {
+        params.push(Box::new(role.clone()));
+        conditions.push(format!("EXISTS (SELECT 1 FROM user_roles WHERE user_id = users.id AND role = ${})", params.len()));
+    }
+
+    if let Some(banned) = query.banned
+    {
+        params.push(Box::new(banned));
+        conditions.push(format!("{} = ${}", banned!(), params.len()));
+    }
+
+    if let Some(domain) = &query.email_domain
+    {
+        params.push(Box::new(domain.clone()));
+        conditions.push(format!("lower(split_part(email, '@', 2)) = lower(${})", params.len()));
+    }
+
+    if let Some(time) = query.created_after
+    {
+        params.push(Box::new(time));
+        conditions.push(format!("created_at > ${}", params.len()));
+    }
+
+    if let Some(time) = query.created_before
+    {
+        params.push(Box::new(time));
+        conditions.push(format!("created_at < ${}", params.len()));
+    }
+
+    let column = query.sort.column();
+    let (comparison, direction) = match query.descending
+    {
+        true => ("<", "DESC"),
+        false => (">", "ASC"),
+    };
+
+    if let Some(cursor) = &query.after
+    {
+        match cursor.key()?
+        {
+            SortKey::Time(time) => params.push(Box::new(time)),
+            SortKey::Text(text) => params.push(Box::new(text)),
+        }
+
+        params.push(Box::new(cursor.id));
+
+        conditions.push(format!("({}, id) {} (${}, ${})", column, comparison, params.len() - 1, params.len()));
+    }
+
+    let filter = match conditions.is_empty()
+    {
+        true => String::new(),
+        false => format!("WHERE {}", conditions.join(" AND ")),
+    };
+
+    params.push(Box::new(query.limit + 1));
+
+    let sql = format!("SELECT {} FROM users {} ORDER BY {} {}, id {} LIMIT ${}", USER_COLUMNS, filter, column, direction, direction, params.len());
+
+    Ok((sql, params))
+}
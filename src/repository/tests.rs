@@ -0,0 +1,309 @@
+// the same cases run against the memory store and, when TEST_DATABASE_URL points at a PostgreSQL database
+// that may be migrated and written to, against PostgreSQL
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use uuid::Uuid;
+
+use crate::audit::Audit;
+use crate::db::{self, PoolOptions};
+use crate::feed::Feed;
+use crate::listener;
+use crate::migrate;
+use crate::outbox::OutboxEvent;
+use crate::pagination::ListQuery;
+use crate::router::Query;
+use crate::users::User;
+
+use super::{MemoryUserRepository, NewUser, OutboxRepository, PostgresUserRepository, Restore, UserRepository};
+
+fn memory() -> Arc<MemoryUserRepository>
+{
+    Arc::new(MemoryUserRepository::new(Feed::new(16)))
+}
+
+async fn postgres() -> Option<Arc<PostgresUserRepository>>
+{
+    let url = std::env::var("TEST_DATABASE_URL").ok()?;
+
+    migrate::run(&url, false).await.expect("the test database can be migrated");
+
+    let pool = db::create_pool(&url, &PoolOptions { min_size: 1, max_size: 4, acquire_timeout: Duration::from_secs(5) }).await.expect("the test database is reachable");
+
+    Some(Arc::new(PostgresUserRepository::new(pool)))
+}
+
+// a domain of its own for every test, so the users other tests leave in a shared database are filtered out
+fn domain() -> String
+{
+    format!("t{}.test", Uuid::new_v4().to_simple())
+}
+
+async fn create(users: &dyn UserRepository, name: &str, local: &str, domain: &str) -> User
+{
+    users.create(NewUser { name: name.to_string(), email: format!("{}@{}", local, domain) }, &Audit::system()).await.unwrap()
+}
+
+// every page of the listing, following the next links
+async fn list_all(users: &dyn UserRepository, query: &str) -> Vec<Vec<User>>
+{
+    let mut pages = Vec::new();
+    let mut query = query.to_string();
+
+    loop
+    {
+        let page = users.list(&ListQuery::from_query(&Query::parse(Some(&query)).unwrap()).unwrap()).await.unwrap();
+
+        pages.push(page.users);
+
+        match page.next
+        {
+            Some(next) => query = next.split_once('?').unwrap().1.to_string(),
+            None => return pages,
+        }
+    }
+}
+
+fn names(pages: &[Vec<User>]) -> Vec<Vec<&str>>
+{
+    pages.iter().map(|page| page.iter().map(|user| user.name.as_str()).collect()).collect()
+}
+
+async fn pages_follow_the_sort_order(users: &dyn UserRepository)
+{
+    let domain = domain();
+
+    for (name, local) in [("carol", "c"), ("alice", "e"), ("bob", "a"), ("dave", "d"), ("bob", "b")]
+    {
+        create(users, name, local, &domain).await;
+
+        // so no two users share a creation time
+        tokio::time::sleep(Duration::from_millis(2)).await;
+    }
+
+    let by_name = list_all(users, &format!("email_domain={}&limit=2", domain)).await;
+
+    assert_eq!(by_name.len(), 3);
+    assert_eq!(by_name.concat().iter().map(|user| user.name.as_str()).collect::<Vec<_>>(), ["alice", "bob", "bob", "carol", "dave"]);
+
+    // users with the same name are ordered by id, on either side of a page boundary
+    let bobs: Vec<Uuid> = by_name.concat().iter().filter(|user| user.name == "bob").map(|user| user.id.unwrap()).collect();
+    assert!(bobs[0] < bobs[1]);
+
+    let by_email = list_all(users, &format!("email_domain={}&limit=2&sort=-email", domain)).await;
+
+    assert_eq!(names(&by_email), [vec!["alice", "dave"], vec!["carol", "bob"], vec!["bob"]]);
+
+    let by_created = list_all(users, &format!("email_domain={}&limit=4&sort=created", domain)).await;
+
+    assert_eq!(names(&by_created), [vec!["carol", "alice", "bob", "dave"], vec!["bob"]]);
+}
+
+async fn filters_apply_to_every_page(users: &dyn UserRepository)
+{
+    let domain = domain();
+
+    let gone = create(users, "gone", "g", &domain).await;
+
+    for name in ["x", "y", "z"]
+    {
+        create(users, name, name, &domain).await;
+    }
+
+    users.delete(gone.id.unwrap(), &Audit::system()).await.unwrap();
+
+    // the domain is compared without regard to case
+    let listed = list_all(users, &format!("email_domain={}&limit=2", domain.to_uppercase())).await;
+
+    assert_eq!(names(&listed), [vec!["x", "y"], vec!["z"]]);
+
+    let with_deleted = list_all(users, &format!("email_domain={}&limit=2&include_deleted=true", domain)).await;
+
+    assert_eq!(names(&with_deleted), [vec!["gone", "x"], vec!["y", "z"]]);
+}
+
+// deleting and restoring a user are not changes to them
+async fn delete_and_restore_keep_updated_at(users: &dyn UserRepository)
+{
+    let domain = domain();
+    let created = create(users, "kept", "k", &domain).await;
+    let id = created.id.unwrap();
+
+    // so a new updated_at would differ
+    tokio::time::sleep(Duration::from_millis(2)).await;
+
+    users.delete(id, &Audit::system()).await.unwrap();
+
+    let deleted = list_all(users, &format!("email_domain={}&include_deleted=true", domain)).await.concat();
+
+    assert!(deleted[0].deleted_at.is_some());
+    assert_eq!(deleted[0].updated_at, created.updated_at);
+
+    let restored = match users.restore(id, SystemTime::UNIX_EPOCH, &Audit::system()).await.unwrap()
+    {
+        Restore::Restored(user) => user,
+        _ => panic!("the user was deleted a moment ago"),
+    };
+
+    assert_eq!(restored.updated_at, created.updated_at);
+}
+
+// the events of these users among the claimed ones, the outbox of a shared database has others as well
+fn claimed_of(claimed: Vec<(OutboxEvent, i32)>, users: &[&User]) -> Vec<(Uuid, &'static str, i32)>
+{
+    claimed.into_iter().filter(|(event, _)| users.iter().any(|user| user.id == Some(event.user_id))).map(|(event, attempts)| (event.user_id, event.kind.name(), attempts)).collect()
+}
+
+async fn refused_events_hold_up_their_user_only<R: UserRepository + OutboxRepository>(store: &R)
+{
+    let domain = domain();
+
+    let held = create(store, "held", "h", &domain).await;
+    let other = create(store, "other", "o", &domain).await;
+    let (held_id, other_id) = (held.id.unwrap(), other.id.unwrap());
+
+    store.delete(held_id, &Audit::system()).await.unwrap();
+
+    let lease = Duration::from_secs(60);
+
+    let claimed = store.claim_events(10_000, lease).await.unwrap();
+    let refused = claimed.iter().find(|(event, _)| event.user_id == held_id).unwrap().0.id;
+    let delivered = claimed.iter().find(|(event, _)| event.user_id == other_id).unwrap().0.id;
+
+    assert_eq!(claimed_of(claimed, &[&held, &other]), [(held_id, "UserCreated", 0), (other_id, "UserCreated", 0), (held_id, "UserDeleted", 0)]);
+
+    // the claimed events are left alone while the lease lasts
+    assert_eq!(claimed_of(store.claim_events(10_000, lease).await.unwrap(), &[&held, &other]), []);
+
+    store.failed(refused, "refused", SystemTime::now() + Duration::from_secs(3600)).await.unwrap();
+    store.delivered(delivered).await.unwrap();
+
+    let other_event = create(store, "other", "o2", &domain).await;
+
+    // the deleted event of the held user waits behind the refused one, the new user is not held up
+    assert_eq!(claimed_of(store.claim_events(10_000, lease).await.unwrap(), &[&held, &other, &other_event]), [(other_event.id.unwrap(), "UserCreated", 0)]);
+
+    store.failed(refused, "refused", SystemTime::now()).await.unwrap();
+
+    assert_eq!(claimed_of(store.claim_events(10_000, lease).await.unwrap(), &[&held]), [(held_id, "UserCreated", 2), (held_id, "UserDeleted", 0)]);
+}
+
+#[tokio::test]
+async fn memory_pages_follow_the_sort_order()
+{
+    pages_follow_the_sort_order(memory().as_ref()).await;
+}
+
+#[tokio::test]
+async fn memory_filters_apply_to_every_page()
+{
+    filters_apply_to_every_page(memory().as_ref()).await;
+}
+
+#[tokio::test]
+async fn memory_delete_and_restore_keep_updated_at()
+{
+    delete_and_restore_keep_updated_at(memory().as_ref()).await;
+}
+
+#[tokio::test]
+async fn memory_refused_events_hold_up_their_user_only()
+{
+    refused_events_hold_up_their_user_only(memory().as_ref()).await;
+}
+
+// the memory store compares text byte by byte, PostgreSQL by the collation of the database
+#[tokio::test]
+async fn memory_sorts_text_by_bytes()
+{
+    let users = memory();
+    let domain = domain();
+
+    for name in ["émile", "bob", "Zoe", "adam"]
+    {
+        create(users.as_ref(), name, name.trim_start_matches('é'), &domain).await;
+    }
+
+    let listed = list_all(users.as_ref(), &format!("email_domain={}", domain)).await;
+
+    assert_eq!(names(&listed), [vec!["Zoe", "adam", "bob", "émile"]]);
+}
+
+#[tokio::test]
+async fn postgres_pages_follow_the_sort_order()
+{
+    if let Some(users) = postgres().await
+    {
+        pages_follow_the_sort_order(users.as_ref()).await;
+    }
+}
+
+#[tokio::test]
+async fn postgres_filters_apply_to_every_page()
+{
+    if let Some(users) = postgres().await
+    {
+        filters_apply_to_every_page(users.as_ref()).await;
+    }
+}
+
+#[tokio::test]
+async fn postgres_delete_and_restore_keep_updated_at()
+{
+    if let Some(users) = postgres().await
+    {
+        delete_and_restore_keep_updated_at(users.as_ref()).await;
+    }
+}
+
+#[tokio::test]
+async fn postgres_refused_events_hold_up_their_user_only()
+{
+    if let Some(store) = postgres().await
+    {
+        refused_events_hold_up_their_user_only(store.as_ref()).await;
+    }
+}
+
+// an edit made in SQL reaches the feed through the users trigger, one made by the service only once through the outbox
+#[tokio::test]
+async fn postgres_changes_made_in_sql_reach_the_feed()
+{
+    let users = match postgres().await
+    {
+        Some(users) => users,
+        None => return,
+    };
+
+    let url = std::env::var("TEST_DATABASE_URL").unwrap();
+
+    let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls).await.unwrap();
+    tokio::spawn(connection);
+
+    let feed = Feed::new(256);
+    let mut subscription = feed.subscribe(None);
+
+    listener::spawn(url, feed.clone(), users.clone());
+
+    // until it is listening
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let user = create(users.as_ref(), "listened", "l", &domain()).await;
+    let id = user.id.unwrap();
+
+    client.execute("UPDATE users SET name = 'renamed' WHERE id = $1", &[&id]).await.unwrap();
+
+    let mut heard = Vec::new();
+
+    while heard.len() < 2
+    {
+        let event = tokio::time::timeout(Duration::from_secs(5), subscription.receiver.recv()).await.expect("the change is announced").unwrap();
+
+        if event.user_id == id
+        {
+            heard.push((event.kind.name(), event.data["name"].as_str().unwrap_or_default().to_string()));
+        }
+    }
+
+    assert_eq!(heard, [("UserCreated", "listened".to_string()), ("UserUpdated", "renamed".to_string())]);
+}
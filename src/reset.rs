@@ -0,0 +1,99 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use crate::audit::Audit;
+use crate::auth::{hash_token, new_token};
+use crate::error::ApiError;
+use crate::http::{Request, Response};
+use crate::mailer::{self, Mail, Mailer};
+use crate::password::{self, Hasher};
+use crate::repository::{NewPasswordReset, PasswordResetRepository, ResetRequest, SendLimit};
+use crate::timestamp;
+use crate::users::parse_json;
+
+// how password reset emails are sent
+pub struct PasswordReset
+{
+    pub mailer: Arc<dyn Mailer>,
+    pub token_ttl: Duration,
+
+    // tokens sent to one user
+    pub user_limit: SendLimit,
+
+    // requests made from one address
+    pub ip_limit: SendLimit,
+
+    // where the email points to with {token} filled in, nothing but the token when empty
+    pub link: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Forgotten
+{
+    email: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Confirm
+{
+    token: String,
+    new_password: String,
+}
+
+// mail a token to the user with the email, answering the same whether or not there is one so accounts cannot be probed
+pub async fn handle_request(req: &Request, resets: &dyn PasswordResetRepository, reset: &PasswordReset, source_ip: IpAddr) -> Result<Response, ApiError>
+{
+    let forgotten: Forgotten = parse_json(req)?;
+
+    let token = new_token();
+    let expires_at = SystemTime::now() + reset.token_ttl;
+
+    let new = NewPasswordReset { hash: hash_token(&token), email: forgotten.email, source_ip, expires_at };
+
+    match resets.create_password_reset(new, reset.user_limit, reset.ip_limit).await?
+    {
+        ResetRequest::Issued(user) =>
+        {
+            let link = reset.link.replace("{token}", &token);
+
+            let body = match link.is_empty()
+            {
+                true => format!("Use this token to choose a new password:\n\n{}\n\nIt expires at {}. If you did not ask for it, ignore this email.\n", token, timestamp::format(expires_at)),
+                false => format!("Open this link to choose a new password:\n\n{}\n\nIt expires at {}. If you did not ask for it, ignore this email.\n", link, timestamp::format(expires_at)),
+            };
+
+            mailer::spawn_send(reset.mailer.clone(), Mail { to: user.email.clone(), subject: "Reset your password".to_string(), body }, user.id.unwrap_or_default());
+        }
+        ResetRequest::Ignored => {}
+
+        // the limit of the address says nothing about the account
+        ResetRequest::Throttled(wait) => return Err(ApiError::TooManyRequests(wait)),
+    }
+
+    Ok(Response::new(202))
+}
+
+// set a new password with a token from the email, which logs the user out everywhere
+pub async fn handle_confirm_request(req: &Request, resets: &dyn PasswordResetRepository, hasher: &Hasher, audit: &Audit) -> Result<Response, ApiError>
+{
+    let confirm: Confirm = parse_json(req)?;
+
+    if let Some(error) = password::validate("new_password", &confirm.new_password)
+    {
+        return Err(ApiError::Validation(vec![error]));
+    }
+
+    let hash = hasher.hash(&confirm.new_password).await?;
+
+    if !resets.reset_password(&hash_token(&confirm.token), &hash, audit).await?
+    {
+        return Err(ApiError::InvalidResetToken);
+    }
+
+    Ok(Response::new(204))
+}
@@ -0,0 +1,180 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::access::Principal;
+use crate::audit::Audit;
+use crate::error::ApiError;
+use crate::http::Response;
+use crate::repository::{RoleRepository, UserRepository};
+use crate::router::Params;
+
+// what a role allows beyond managing one's own account, stored by name in the roles table
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Permission
+{
+    ReadUsers,
+    ListUsers,
+    EditUsers,
+    BanUsers,
+    DeleteUsers,
+    ManageRoles,
+    ReadAudit,
+    ManageWebhooks,
+}
+
+impl Permission
+{
+    pub fn name(self) -> &'static str
+    {
+        match self
+        {
+            Permission::ReadUsers => "users:read",
+            Permission::ListUsers => "users:list",
+            Permission::EditUsers => "users:edit",
+            Permission::BanUsers => "users:ban",
+            Permission::DeleteUsers => "users:delete",
+            Permission::ManageRoles => "roles:manage",
+            Permission::ReadAudit => "audit:read",
+            Permission::ManageWebhooks => "webhooks:manage",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Permission>
+    {
+        match name
+        {
+            "users:read" => Some(Permission::ReadUsers),
+            "users:list" => Some(Permission::ListUsers),
+            "users:edit" => Some(Permission::EditUsers),
+            "users:ban" => Some(Permission::BanUsers),
+            "users:delete" => Some(Permission::DeleteUsers),
+            "roles:manage" => Some(Permission::ManageRoles),
+            "audit:read" => Some(Permission::ReadAudit),
+            "webhooks:manage" => Some(Permission::ManageWebhooks),
+            _ => None,
+        }
+    }
+}
+
+// roles every installation has, migration 0005 seeds the roles table with the same definitions
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Role
+{
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role
+{
+    pub const ALL: [Role; 3] = [Role::User, Role::Moderator, Role::Admin];
+
+    // the role of new users
+    pub const DEFAULT: Role = Role::User;
+
+    pub fn name(self) -> &'static str
+    {
+        match self
+        {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Role>
+    {
+        Role::ALL.into_iter().find(|role| role.name() == name)
+    }
+
+    pub fn description(self) -> &'static str
+    {
+        match self
+        {
+            Role::User => "Manages their own account.",
+            Role::Moderator => "Reads and lists every user, bans and unbans them.",
+            Role::Admin => "Does everything.",
+        }
+    }
+
+    pub fn permissions(self) -> &'static [Permission]
+    {
+        match self
+        {
+            Role::User => &[],
+            Role::Moderator => &[Permission::ReadUsers, Permission::ListUsers, Permission::BanUsers],
+            Role::Admin => &[Permission::ReadUsers, Permission::ListUsers, Permission::EditUsers, Permission::BanUsers, Permission::DeleteUsers, Permission::ManageRoles, Permission::ReadAudit, Permission::ManageWebhooks],
+        }
+    }
+
+    pub fn info(self) -> RoleInfo
+    {
+        RoleInfo
+        {
+            name: self.name().to_string(),
+            description: self.description().to_string(),
+            permissions: self.permissions().iter().map(|permission| permission.name().to_string()).collect(),
+            built_in: true,
+        }
+    }
+}
+
+// a row of the roles table
+#[derive(Serialize)]
+pub struct RoleInfo
+{
+    pub name: String,
+    pub description: String,
+    pub permissions: Vec<String>,
+    pub built_in: bool,
+}
+
+// list every role and its permissions
+pub async fn handle_list_request(roles: &dyn RoleRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    principal.active()?;
+
+    let roles = roles.list_roles().await?;
+
+    Ok(Response::json(200, serde_json::json!({ "data": roles }).to_string()))
+}
+
+// give a role to a user with the matching id, answering with the updated user
+pub async fn handle_assign_request(params: &Params, users: &dyn UserRepository, roles: &dyn RoleRepository, audit: &Audit, principal: &Principal) -> Result<Response, ApiError>
+{
+    let (id, role) = role_params(params)?;
+
+    principal.active()?.can_manage_roles()?;
+
+    if !roles.assign_role(id, &role, audit).await?
+    {
+        return Err(ApiError::UserNotFound);
+    }
+
+    let user = users.get(id).await?.ok_or(ApiError::UserNotFound)?;
+
+    Ok(Response::json(200, serde_json::to_string(&user).unwrap()))
+}
+
+// take a role away from a user with the matching id
+pub async fn handle_revoke_request(params: &Params, roles: &dyn RoleRepository, audit: &Audit, principal: &Principal) -> Result<Response, ApiError>
+{
+    let (id, role) = role_params(params)?;
+
+    principal.active()?.can_manage_roles()?;
+
+    if !roles.revoke_role(id, &role, audit).await?
+    {
+        return Err(ApiError::UserNotFound);
+    }
+
+    Ok(Response::new(204))
+}
+
+fn role_params(params: &Params) -> Result<(Uuid, String), ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+    let role: String = params.get("role").ok_or(ApiError::RoleNotFound)?;
+
+    Ok((id, role))
+}
@@ -0,0 +1,341 @@
+use std::str::FromStr;
+
+// methods the server understands, in the order they are listed in Allow headers
+const KNOWN_METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+pub struct Router<T>
+{
+    routes: Vec<Route<T>>,
+}
+
+struct Route<T>
+{
+    method: &'static str,
+    segments: Vec<Segment>,
+    endpoint: T,
+}
+
+enum Segment
+{
+    Literal(&'static str),
+    Param(&'static str),
+}
+
+pub enum Match<T>
+{
+    // a route matched, HEAD requests are matched against GET routes
+    Found(T, Params),
+
+    // OPTIONS request for a known path
+    Options(String),
+
+    // the path exists but not for this method, carries the Allow header value
+    MethodNotAllowed(String),
+
+    NotFound,
+}
+
+impl<T: Copy> Router<T>
+{
+    pub fn new() -> Self
+    {
+        Router { routes: Vec::new() }
+    }
+
+    // register an endpoint for a method and a path template such as "/users/{id}"
+    pub fn route(mut self, method: &'static str, template: &'static str, endpoint: T) -> Self
+    {
+        let segments = split_path(template).map(|segment|
+        {
+            match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}'))
+            {
+                Some(name) => Segment::Param(name),
+                None => Segment::Literal(segment),
+            }
+        }).collect();
+
+        self.routes.push(Route { method, segments, endpoint });
+        self
+    }
+
+    pub fn resolve(&self, method: &str, path: &str) -> Match<T>
+    {
+        let segments: Vec<&str> = split_path(path).collect();
+
+        let matching: Vec<(&Route<T>, Params)> = self.routes.iter().filter_map(|route| route.matches(&segments).map(|params| (route, params))).collect();
+
+        // only the most specific templates count, so "/users/events" is never taken for "/users/{id}" whatever the method
+        let best = match matching.iter().map(|(route, _)| route.literals()).max()
+        {
+            Some(best) => best,
+            None => return Match::NotFound,
+        };
+
+        let mut allowed = Vec::new();
+
+        for (route, params) in matching.into_iter().filter(|(route, _)| route.literals() == best)
+        {
+            if route.method == method || (method == "HEAD" && route.method == "GET")
+            {
+                return Match::Found(route.endpoint, params);
+            }
+
+            allowed.push(route.method);
+        }
+
+        // HEAD and OPTIONS are answered by the router itself
+        if allowed.contains(&"GET")
+        {
+            allowed.push("HEAD");
+        }
+
+        allowed.push("OPTIONS");
+
+        let allow = KNOWN_METHODS.iter().filter(|m| allowed.contains(m)).copied().collect::<Vec<_>>().join(", ");
+
+        match method
+        {
+            "OPTIONS" => Match::Options(allow),
+            _ => Match::MethodNotAllowed(allow),
+        }
+    }
+}
+
+impl<T> Route<T>
+{
+    // which segments are literals, a literal sorts before a parameter in the same place
+    fn literals(&self) -> Vec<bool>
+    {
+        self.segments.iter().map(|segment| matches!(segment, Segment::Literal(_))).collect()
+    }
+
+    fn matches(&self, segments: &[&str]) -> Option<Params>
+    {
+        if segments.len() != self.segments.len()
+        {
+            return None;
+        }
+
+        let mut params = Params::default();
+
+        for (template, segment) in self.segments.iter().zip(segments)
+        {
+            match template
+            {
+                Segment::Literal(literal) if literal == segment => {}
+                Segment::Param(name) if !segment.is_empty() => params.values.push((name, percent_decode(segment)?)),
+
+                _ => return None,
+            }
+        }
+
+        Some(params)
+    }
+}
+
+// values captured from the path template
+#[derive(Default)]
+pub struct Params
+{
+    values: Vec<(&'static str, String)>,
+}
+
+impl Params
+{
+    // parse a captured segment, returns None if it is missing or does not parse
+    pub fn get<V: FromStr>(&self, name: &str) -> Option<V>
+    {
+        self.values.iter().find(|(key, _)| *key == name).and_then(|(_, value)| value.parse().ok())
+    }
+}
+
+// decoded key/value pairs from a query string
+pub struct Query
+{
+    values: Vec<(String, String)>,
+}
+
+impl Query
+{
+    // returns None if the query string is not properly percent-encoded
+    pub fn parse(query: Option<&str>) -> Option<Self>
+    {
+        let mut values = Vec::new();
+
+        for pair in query.unwrap_or_default().split('&').filter(|pair| !pair.is_empty())
+        {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+            values.push((decode_component(key)?, decode_component(value)?));
+        }
+
+        Some(Query { values })
+    }
+
+    // get the last value for a key, Err with the raw value if it does not parse
+    pub fn get<V: FromStr>(&self, name: &str) -> Result<Option<V>, String>
+    {
+        match self.values.iter().rev().find(|(key, _)| key == name)
+        {
+            Some((_, value)) => value.parse().map(Some).map_err(|_| value.clone()),
+            None => Ok(None),
+        }
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str>
+{
+    // the leading slash does not produce a segment, a trailing one does
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+// form encoding also allows '+' for spaces
+fn decode_component(value: &str) -> Option<String>
+{
+    percent_decode(&value.replace('+', " "))
+}
+
+// encode a value so it can be placed in a query string
+pub fn percent_encode(value: &str) -> String
+{
+    value.bytes().map(|b| match b
+    {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+fn percent_decode(value: &str) -> Option<String>
+{
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+
+    while i < bytes.len()
+    {
+        if bytes[i] == b'%'
+        {
+            let hex = bytes.get(i + 1..i + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        }
+        else
+        {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests
+{
+    use uuid::Uuid;
+
+    use super::*;
+
+    // the parameter route comes first, the literal one has to win anyway
+    fn router() -> Router<&'static str>
+    {
+        Router::new()
+            .route("GET", "/users", "list")
+            .route("POST", "/users", "create")
+            .route("GET", "/users/{id}", "get")
+            .route("PUT", "/users/{id}", "update")
+            .route("DELETE", "/users/{id}", "delete")
+            .route("GET", "/users/events", "events")
+    }
+
+    fn found(method: &str, path: &str) -> Option<&'static str>
+    {
+        match router().resolve(method, path)
+        {
+            Match::Found(endpoint, _) => Some(endpoint),
+            _ => None,
+        }
+    }
+
+    fn allow(method: &str, path: &str) -> Option<String>
+    {
+        match router().resolve(method, path)
+        {
+            Match::MethodNotAllowed(allow) | Match::Options(allow) => Some(allow),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn paths_match_whole_segments()
+    {
+        assert_eq!(found("GET", "/users"), Some("list"));
+
+        for path in ["/usersXYZ", "/users/", "/user", "/users/1/2", "/"]
+        {
+            assert!(matches!(router().resolve("GET", path), Match::NotFound), "{}", path);
+        }
+
+        assert!(matches!(router().resolve("POST", "/usersXYZ"), Match::NotFound));
+    }
+
+    #[test]
+    fn other_methods_get_the_allow_list()
+    {
+        assert!(matches!(router().resolve("POST", "/users/1"), Match::MethodNotAllowed(_)));
+        assert_eq!(allow("POST", "/users/1").as_deref(), Some("GET, HEAD, PUT, DELETE, OPTIONS"));
+        assert_eq!(allow("DELETE", "/users").as_deref(), Some("GET, HEAD, POST, OPTIONS"));
+    }
+
+    #[test]
+    fn head_and_options_are_answered_for_known_paths()
+    {
+        assert_eq!(found("HEAD", "/users/1"), Some("get"));
+        assert!(matches!(router().resolve("HEAD", "/nowhere"), Match::NotFound));
+
+        assert!(matches!(router().resolve("OPTIONS", "/users"), Match::Options(_)));
+        assert_eq!(allow("OPTIONS", "/users").as_deref(), Some("GET, HEAD, POST, OPTIONS"));
+    }
+
+    #[test]
+    fn literal_segments_win_over_parameters()
+    {
+        assert_eq!(found("GET", "/users/events"), Some("events"));
+        assert_eq!(allow("DELETE", "/users/events").as_deref(), Some("GET, HEAD, OPTIONS"));
+        assert_eq!(found("DELETE", "/users/event"), Some("delete"));
+    }
+
+    #[test]
+    fn params_are_typed_and_decoded()
+    {
+        let id = Uuid::new_v4();
+
+        let params = |path: &str| match router().resolve("GET", path)
+        {
+            Match::Found(_, params) => params,
+            _ => panic!("{} is a user", path),
+        };
+
+        assert_eq!(params(&format!("/users/{}", id)).get::<Uuid>("id"), Some(id));
+        assert_eq!(params("/users/not-a-uuid").get::<Uuid>("id"), None);
+        assert_eq!(params("/users/a%20b").get::<String>("id").as_deref(), Some("a b"));
+        assert_eq!(params("/users/1").get::<String>("other"), None);
+    }
+
+    #[test]
+    fn malformed_escapes_are_refused()
+    {
+        assert_eq!(percent_decode("caf%C3%A9").as_deref(), Some("café"));
+
+        for value in ["%", "%4", "%zz", "%4g", "%FF", "%C3"]
+        {
+            assert_eq!(percent_decode(value), None, "{}", value);
+        }
+
+        assert!(matches!(router().resolve("GET", "/users/%zz"), Match::NotFound));
+        assert!(Query::parse(Some("name=%FF")).is_none());
+        assert_eq!(Query::parse(Some("name=a+b%2Bc")).unwrap().get::<String>("name"), Ok(Some("a b+c".to_string())));
+    }
+}
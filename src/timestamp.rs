@@ -0,0 +1,65 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer, Serializer};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+// timestamps in JSON are RFC 3339 strings in UTC with at most microseconds, the precision of PostgreSQL,
+// e.g. 2026-10-15T08:30:00.123456Z
+pub fn format(time: SystemTime) -> String
+{
+    let time = OffsetDateTime::from(time);
+    let time = time.replace_microsecond(time.microsecond()).expect("microseconds are in range");
+
+    time.format(&Rfc3339).expect("every SystemTime fits RFC 3339")
+}
+
+// the current time truncated to microseconds, so what is kept in memory compares like what is read back from PostgreSQL
+pub fn now() -> SystemTime
+{
+    UNIX_EPOCH + Duration::from_micros(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64)
+}
+
+// any offset is accepted, not just Z
+pub fn parse(value: &str) -> Option<SystemTime>
+{
+    OffsetDateTime::parse(value, &Rfc3339).ok().map(SystemTime::from)
+}
+
+pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
+{
+    serializer.serialize_str(&format(*time))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error>
+{
+    let value = String::deserialize(deserializer)?;
+
+    parse(&value).ok_or_else(|| serde::de::Error::custom(format!("'{}' is not an RFC 3339 timestamp", value)))
+}
+
+// #[serde(with = "timestamp::option")] for fields that can be null
+pub mod option
+{
+    use std::time::SystemTime;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        match time
+        {
+            Some(time) => super::serialize(time, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SystemTime>, D::Error>
+    {
+        match Option::<String>::deserialize(deserializer)?
+        {
+            Some(value) => super::parse(&value).map(Some).ok_or_else(|| serde::de::Error::custom(format!("'{}' is not an RFC 3339 timestamp", value))),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::access::Principal;
+use crate::audit::Audit;
+use crate::error::{ApiError, FieldError};
+use crate::http::{Request, Response};
+use crate::pagination::ListQuery;
+use crate::password::{self, Hasher};
+use crate::patch::{self, PatchError};
+use crate::repository::{CredentialRepository, NewUser, Restore, UserChanges, UserRepository, VerificationRepository};
+use crate::router::{Params, Query};
+use crate::timestamp;
+use crate::verification::{self, EmailVerification};
+
+// fields clients may send back unchanged (or leave out) but cannot change
+const READ_ONLY: [&str; 7] = ["roles", "banned", "email_verified", "created_at", "updated_at", "last_login_at", "deleted_at"];
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct User
+{
+    pub id: Option<Uuid>,
+
+    pub name: String,
+    pub email: String,
+
+    // read-only, set once the user proves the email is theirs (see /users/{id}/verify-email) and cleared when it changes
+    pub email_verified: Option<bool>,
+
+    // read-only here, roles are granted through /users/{id}/roles
+    pub roles: Option<Vec<String>>,
+
+    // read-only, true while the user has an active ban (see /users/{id}/bans)
+    pub banned: Option<bool>,
+
+    // read-only, maintained by the repository
+    #[serde(default, with = "timestamp::option")]
+    pub created_at: Option<SystemTime>,
+
+    // last change to the name or email
+    #[serde(default, with = "timestamp::option")]
+    pub updated_at: Option<SystemTime>,
+
+    // None until the first login
+    #[serde(default, with = "timestamp::option")]
+    pub last_login_at: Option<SystemTime>,
+
+    // set while the user is deleted but can still be restored, see /users/{id}/restore
+    #[serde(default, with = "timestamp::option")]
+    pub deleted_at: Option<SystemTime>,
+}
+
+// the body of POST /users, a user and optionally their first password
+#[derive(Deserialize)]
+struct Registration
+{
+    #[serde(flatten)]
+    user: User,
+
+    password: Option<String>,
+}
+
+// get a user with the matching id, deleted users only with include_deleted=true
+pub async fn handle_get_request(req: &Request, params: &Params, users: &dyn UserRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_read(id)?;
+
+    let query = Query::parse(req.query.as_deref()).ok_or(ApiError::MalformedRequest("invalid percent-encoding in query string"))?;
+    let include_deleted = query.get("include_deleted").map_err(|value| ApiError::InvalidQuery(format!("'{}' is not a valid include_deleted, expected true or false", value)))?.unwrap_or(false);
+
+    let user = match include_deleted
+    {
+        true =>
+        {
+            principal.can_delete()?;
+            users.get_including_deleted(id).await?
+        }
+        false => users.get(id).await?,
+    };
+
+    let user = user.ok_or(ApiError::UserNotFound)?;
+
+    Ok(Response::json(200, serde_json::to_string(&user).unwrap()))
+}
+
+// get a page of users, filtered and sorted according to the query string
+pub async fn handle_get_all_request(req: &Request, users: &dyn UserRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    principal.active()?.can_list()?;
+
+    let query = Query::parse(req.query.as_deref()).ok_or(ApiError::MalformedRequest("invalid percent-encoding in query string"))?;
+    let list = ListQuery::from_query(&query)?;
+
+    // only those who can delete users get to see the deleted ones
+    if list.include_deleted
+    {
+        principal.can_delete()?;
+    }
+
+    let page = users.list(&list).await?;
+    let response = Response::json(200, serde_json::to_string(&page.users).unwrap());
+
+    // the body is the bare array it always was, the following page is linked in a header (RFC 8288)
+    match page.next
+    {
+        Some(next) => Ok(response.header("Link", &format!("<{}>; rel=\"next\"", next))),
+        None => Ok(response),
+    }
+}
+
+// add a user, with a password so they can log in right away or without one to set it later through a password reset
+pub async fn handle_post_request(req: &Request, users: &dyn UserRepository, credentials: &dyn CredentialRepository, hasher: &Hasher, verifications: &dyn VerificationRepository, verification: &EmailVerification, audit: &Audit) -> Result<Response, ApiError>
+{
+    let Registration { user, password } = parse_json(req)?;
+
+    validate_user(&user)?;
+
+    if let Some(error) = password.as_deref().and_then(|password| password::validate("password", password))
+    {
+        return Err(ApiError::Validation(vec![error]));
+    }
+
+    // check if the email already exists
+    if users.exists_by_email(&user.email).await?
+    {
+        return Err(ApiError::DuplicateEmail);
+    }
+
+    // hashed before the user exists, so a failure does not leave them without the password they chose
+    let hash = match password
+    {
+        Some(password) => Some(hasher.hash(&password).await?),
+        None => None,
+    };
+
+    let created = users.create(NewUser { name: user.name, email: user.email }, audit).await?;
+    let location = format!("/users/{}", created.id.unwrap_or_default());
+
+    // part of the registration, which is audited as the user being created
+    if let Some(hash) = hash
+    {
+        credentials.set_password_hash(created.id.unwrap_or_default(), &hash, None).await?;
+    }
+
+    verification::send_for_new_email(created.id.unwrap_or_default(), verifications, verification).await;
+
+    Ok(Response::json(201, serde_json::to_string(&created).unwrap()).header("Location", &location))
+}
+
+// update a user with the matching id
+pub async fn handle_put_request(req: &Request, params: &Params, users: &dyn UserRepository, verifications: &dyn VerificationRepository, verification: &EmailVerification, audit: &Audit, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    // nobody can change a user they cannot see
+    principal.active()?.can_read(id)?;
+
+    let body: serde_json::Value = parse_json(req)?;
+    let user: User = serde_json::from_value(body.clone()).map_err(|e| ApiError::InvalidJson(e.to_string()))?;
+
+    validate_user(&user)?;
+
+    // the read-only fields can be omitted
+    let changes = UserChanges
+    {
+        name: Some(user.name),
+        email: Some(user.email),
+    };
+
+    // only the closure sees the stored email
+    let email_changed = AtomicBool::new(false);
+
+    // which fields may change depends on who the stored user is, so it is checked against that
+    let apply = |current: &User|
+    {
+        let errors = body.as_object().map(|fields| read_only_errors(current, fields)).unwrap_or_default();
+
+        if !errors.is_empty()
+        {
+            return Err(ApiError::Validation(errors));
+        }
+
+        principal.can_change(current, &changes)?;
+        email_changed.store(changes.email.as_ref() != Some(&current.email), Ordering::Relaxed);
+        Ok(changes.clone())
+    };
+
+    // no user matched the id
+    let updated = users.update(id, &apply, audit).await?.ok_or(ApiError::UserNotFound)?;
+
+    if email_changed.load(Ordering::Relaxed)
+    {
+        verification::send_for_new_email(id, verifications, verification).await;
+    }
+
+    Ok(Response::json(200, serde_json::to_string(&updated).unwrap()))
+}
+
+// partially update a user with the matching id, only the fields present in the patch are written
+pub async fn handle_patch_request(req: &Request, params: &Params, users: &dyn UserRepository, verifications: &dyn VerificationRepository, verification: &EmailVerification, audit: &Audit, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_read(id)?;
+
+    let media_type = req.header("content-type").unwrap_or_default().split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+
+    let patch: serde_json::Value = match media_type.as_str()
+    {
+        "application/merge-patch+json" | "application/json-patch+json" => parse_json(req)?,
+        _ => return Err(ApiError::UnsupportedPatchType),
+    };
+
+    let email_changed = AtomicBool::new(false);
+
+    // the patch is applied to the stored user inside the repository so nothing can change it in between
+    let apply = |current: &User|
+    {
+        let mut document = serde_json::to_value(current).unwrap();
+
+        match media_type.as_str()
+        {
+            "application/merge-patch+json" => patch::merge_patch(&mut document, &patch),
+            _ => patch::json_patch(&mut document, &patch).map_err(|e| match e
+            {
+                PatchError::Invalid(reason) => ApiError::InvalidPatch(reason),
+                PatchError::TestFailed(reason) => ApiError::PatchTestFailed(reason),
+            })?,
+        }
+
+        let patched = patched_user(current, document)?;
+
+        let changes = UserChanges
+        {
+            name: Some(patched.name).filter(|name| *name != current.name),
+            email: Some(patched.email).filter(|email| *email != current.email),
+        };
+
+        principal.can_change(current, &changes)?;
+        email_changed.store(changes.email.is_some(), Ordering::Relaxed);
+
+        Ok(changes)
+    };
+
+    let updated = users.update(id, &apply, audit).await?.ok_or(ApiError::UserNotFound)?;
+
+    if email_changed.load(Ordering::Relaxed)
+    {
+        verification::send_for_new_email(id, verifications, verification).await;
+    }
+
+    Ok(Response::json(200, serde_json::to_string(&updated).unwrap()))
+}
+
+// delete a user with the matching id, they can be restored until the grace period is over
+pub async fn handle_delete_request(params: &Params, users: &dyn UserRepository, audit: &Audit, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_delete()?;
+
+    // nothing was deleted
+    if !users.delete(id, audit).await?
+    {
+        return Err(ApiError::UserNotFound);
+    }
+
+    Ok(Response::new(204))
+}
+
+// undo the delete of a user with the matching id, answering with the restored user
+pub async fn handle_restore_request(params: &Params, users: &dyn UserRepository, grace_period: Duration, audit: &Audit, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_delete()?;
+
+    let user = match users.restore(id, SystemTime::now() - grace_period, audit).await?
+    {
+        Restore::Restored(user) => user,
+        Restore::NotDeleted => return Err(ApiError::NotDeleted),
+        Restore::Expired => return Err(ApiError::RestoreExpired),
+        Restore::NotFound => return Err(ApiError::UserNotFound),
+    };
+
+    Ok(Response::json(200, serde_json::to_string(&user).unwrap()))
+}
+
+// periodically remove the users whose grace period is over, along with everything that belongs to them
+pub fn spawn_purge_task(users: Arc<dyn UserRepository>, grace_period: Duration, interval: Duration)
+{
+    tokio::spawn(async move
+    {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop
+        {
+            ticker.tick().await;
+
+            match users.purge(SystemTime::now() - grace_period, &Audit::system()).await
+            {
+                Ok(purged) if purged.is_empty() => {}
+                Ok(purged) => log::info!("Purged {} deleted users", purged.len()),
+                Err(e) => log::warn!("Cannot purge deleted users: {:?}", e),
+            }
+        }
+    });
+}
+
+pub fn parse_json<T: serde::de::DeserializeOwned>(req: &Request) -> Result<T, ApiError>
+{
+    serde_json::from_slice(&req.body).map_err(|e| ApiError::InvalidJson(e.to_string()))
+}
+
+fn validate_user(user: &User) -> Result<(), ApiError>
+{
+    let errors: Vec<FieldError> = validate_email(&user.email).into_iter().collect();
+
+    match errors.is_empty()
+    {
+        true => Ok(()),
+        false => Err(ApiError::Validation(errors)),
+    }
+}
+
+fn validate_email(email: &str) -> Option<FieldError>
+{
+    let email_regex = Regex::new(r"^[^\s@.]+(\.[^\s@.]+)*@[^\s@.]+(\.[^\s@.]+)+$").unwrap();
+
+    match email_regex.is_match(email)
+    {
+        true => None,
+        false => Some(FieldError::new("email", "invalid_format", "Invalid email format.")),
+    }
+}
+
+// turn a patched JSON document back into a user, validating only the fields that changed
+fn patched_user(current: &User, document: serde_json::Value) -> Result<User, ApiError>
+{
+    let mut fields = match document
+    {
+        serde_json::Value::Object(fields) => fields,
+        _ => return Err(ApiError::InvalidPatch("the patched user must be a JSON object".to_string())),
+    };
+
+    let mut errors = Vec::new();
+
+    let name = take_string(&mut fields, "name", &current.name, &mut errors);
+    let email = take_string(&mut fields, "email", &current.email, &mut errors);
+
+    if fields.remove("id") != Some(serde_json::to_value(current.id).unwrap())
+    {
+        errors.push(FieldError::new("id", "read_only", "Cannot be changed."));
+    }
+
+    // removing the read-only fields from the document leaves them alone
+    errors.extend(read_only_errors(current, &fields));
+    fields.retain(|field, _| !READ_ONLY.contains(&field.as_str()));
+
+    for field in fields.keys()
+    {
+        errors.push(FieldError::new(field, "unknown_field", "Unknown field."));
+    }
+
+    if email != current.email
+    {
+        errors.extend(validate_email(&email));
+    }
+
+    match errors.is_empty()
+    {
+        true => Ok(User { id: current.id, name, email, ..current.clone() }),
+        false => Err(ApiError::Validation(errors)),
+    }
+}
+
+// read-only fields that were sent with a value other than the current one, null counts as left out
+// and the roles can come in any order
+fn read_only_errors(current: &User, fields: &serde_json::Map<String, serde_json::Value>) -> Vec<FieldError>
+{
+    let current = serde_json::to_value(current).unwrap();
+    let sorted = |value: &serde_json::Value| -> serde_json::Value
+    {
+        let mut roles: Vec<String> = serde_json::from_value(value.clone()).unwrap_or_default();
+
+        roles.sort();
+        roles.dedup();
+
+        serde_json::json!(roles)
+    };
+
+    READ_ONLY.iter().filter_map(|&field|
+    {
+        let sent = fields.get(field).filter(|value| !value.is_null())?;
+
+        let changed = match field
+        {
+            "roles" => !sent.is_array() || sorted(sent) != sorted(&current[field]),
+            _ => *sent != current[field],
+        };
+
+        let message = match field
+        {
+            "roles" => "Cannot be changed here, use /users/{id}/roles/{role}.",
+            "banned" => "Cannot be changed here, use /users/{id}/bans.",
+            "email_verified" => "Cannot be changed here, use /users/{id}/verify-email.",
+            _ => "Cannot be changed.",
+        };
+
+        changed.then(|| FieldError::new(field, "read_only", message))
+    }).collect()
+}
+
+// take a required string field out of a patched document, keeping the current value if it is unusable
+fn take_string(fields: &mut serde_json::Map<String, serde_json::Value>, field: &str, current: &str, errors: &mut Vec<FieldError>) -> String
+{
+    match fields.remove(field)
+    {
+        Some(serde_json::Value::String(value)) => value,
+        Some(_) =>
+        {
+            errors.push(FieldError::new(field, "invalid_type", "Must be a string."));
+            current.to_string()
+        }
+        None =>
+        {
+            errors.push(FieldError::new(field, "required", "Cannot be removed."));
+            current.to_string()
+        }
+    }
+}
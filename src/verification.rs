@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::access::Principal;
+use crate::audit::Audit;
+use crate::auth::{hash_token, new_token};
+use crate::error::ApiError;
+use crate::http::{Request, Response};
+use crate::mailer::{self, Mail, Mailer};
+use crate::repository::{Issue, NewVerification, SendLimit, VerificationRepository};
+use crate::router::Params;
+use crate::timestamp;
+use crate::users::parse_json;
+
+// how verification emails are sent
+pub struct EmailVerification
+{
+    pub mailer: Arc<dyn Mailer>,
+    pub token_ttl: Duration,
+    pub limit: SendLimit,
+
+    // where the email points to with {user_id} and {token} filled in, nothing but the token when empty
+    pub link: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Verify
+{
+    token: String,
+}
+
+// mark the email of a user with the matching id verified, the token is what proves it so no credentials are needed
+pub async fn handle_verify_request(req: &Request, params: &Params, verifications: &dyn VerificationRepository, audit: &Audit) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+    let verify: Verify = parse_json(req)?;
+
+    // an unknown user is an invalid token as well, so ids cannot be probed
+    let verified = verifications.verify_email(id, &hash_token(&verify.token), audit).await?.ok_or(ApiError::InvalidVerificationToken)?;
+
+    Ok(Response::json(200, serde_json::to_string(&verified).unwrap()))
+}
+
+// send a new token to the email of a user with the matching id, the earlier ones stay valid until they expire
+pub async fn handle_resend_request(params: &Params, verifications: &dyn VerificationRepository, verification: &EmailVerification, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_resend_verification(id)?;
+
+    match send(id, verifications, verification).await?
+    {
+        Issue::Issued(_) => Ok(Response::new(202)),
+        Issue::AlreadyVerified => Err(ApiError::EmailAlreadyVerified),
+        Issue::Throttled(wait) => Err(ApiError::TooManyRequests(wait)),
+        Issue::NotFound => Err(ApiError::UserNotFound),
+    }
+}
+
+// send a token after the user registered or changed their email, the change stands whether or not it can be sent
+pub async fn send_for_new_email(user_id: Uuid, verifications: &dyn VerificationRepository, verification: &EmailVerification)
+{
+    match send(user_id, verifications, verification).await
+    {
+        Ok(Issue::Throttled(wait)) => log::info!("No verification email sent to user {}, the next one can be sent in {:?}", user_id, wait),
+        Ok(_) => {}
+        Err(e) => log::warn!("Cannot create a verification token for user {}: {:?}", user_id, e),
+    }
+}
+
+// store a new token and mail it in the background
+async fn send(user_id: Uuid, verifications: &dyn VerificationRepository, verification: &EmailVerification) -> Result<Issue, ApiError>
+{
+    let token = new_token();
+    let expires_at = SystemTime::now() + verification.token_ttl;
+
+    let issue = verifications.create_verification(NewVerification { hash: hash_token(&token), user_id, expires_at }, verification.limit).await?;
+
+    if let Issue::Issued(user) = &issue
+    {
+        let link = verification.link.replace("{user_id}", &user_id.to_string()).replace("{token}", &token);
+
+        // the name is left out, anyone can register with someone else's email
+        let body = match link.is_empty()
+        {
+            true => format!("Use this token to verify your email address:\n\n{}\n\nIt expires at {}.\n", token, timestamp::format(expires_at)),
+            false => format!("Open this link to verify your email address:\n\n{}\n\nIt expires at {}.\n", link, timestamp::format(expires_at)),
+        };
+
+        mailer::spawn_send(verification.mailer.clone(), Mail { to: user.email.clone(), subject: "Verify your email address".to_string(), body }, user_id);
+    }
+
+    Ok(issue)
+}
@@ -0,0 +1,379 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+use crate::access::Principal;
+use crate::error::{ApiError, FieldError};
+use crate::http::{self, Request, Response, Url};
+use crate::outbox::Kind;
+use crate::pagination::DeliveryQuery;
+use crate::repository::WebhookRepository;
+use crate::router::{Params, Query};
+use crate::timestamp;
+use crate::users::parse_json;
+
+// secrets are chosen by the subscriber, these keep them from being trivial or absurd
+const MIN_SECRET_LENGTH: usize = 16;
+const MAX_SECRET_LENGTH: usize = 256;
+
+// deliveries claimed per run of the task, they are sent concurrently
+const BATCH_SIZE: usize = 100;
+
+// a webhook that has not answered after this long has failed
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how long a claimed delivery is left to the replica that claimed it, another one sends it again afterwards
+const LEASE: Duration = Duration::from_secs(60);
+
+// the delay doubles after every failed attempt, from FIRST_RETRY up to MAX_RETRY_DELAY,
+// the delivery is given up (dead) after MAX_ATTEMPTS attempts, about 3.5 hours after the first
+const FIRST_RETRY: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+const MAX_ATTEMPTS: i32 = 12;
+
+#[derive(Clone, Serialize)]
+pub struct Webhook
+{
+    pub id: Uuid,
+    pub url: String,
+
+    // created, updated, banned, unbanned or deleted, the outbox events without the User prefix
+    pub events: Vec<String>,
+
+    // never shown after it was set
+    #[serde(skip)]
+    pub secret: String,
+
+    #[serde(with = "timestamp")]
+    pub created_at: SystemTime,
+}
+
+pub struct NewWebhook
+{
+    pub url: String,
+    pub events: Vec<String>,
+    pub secret: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus
+{
+    // waiting for its first or next attempt
+    Pending,
+
+    Delivered,
+
+    // every attempt failed, the delivery is only kept for the log
+    Dead,
+}
+
+impl DeliveryStatus
+{
+    pub fn name(self) -> &'static str
+    {
+        match self
+        {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Dead => "dead",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DeliveryStatus>
+    {
+        [DeliveryStatus::Pending, DeliveryStatus::Delivered, DeliveryStatus::Dead].into_iter().find(|status| status.name() == name)
+    }
+}
+
+// an event on its way to one webhook, with the outcome of the last attempt
+#[derive(Clone, Serialize)]
+pub struct Delivery
+{
+    pub id: i64,
+    pub webhook_id: Uuid,
+
+    // the id of the outbox event, None for a test ping
+    pub event_id: Option<i64>,
+
+    // the topic, or ping
+    pub event: String,
+
+    pub payload: serde_json::Value,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+
+    // only while pending
+    #[serde(with = "timestamp::option")]
+    pub next_attempt_at: Option<SystemTime>,
+
+    // None when the webhook could not be reached
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+
+    #[serde(with = "timestamp")]
+    pub created_at: SystemTime,
+
+    #[serde(with = "timestamp::option")]
+    pub delivered_at: Option<SystemTime>,
+}
+
+pub struct DeliveryPage
+{
+    pub deliveries: Vec<Delivery>,
+
+    // link to the following page, None on the last one
+    pub next: Option<String>,
+}
+
+pub enum Outcome
+{
+    Delivered,
+    Retry(SystemTime),
+    Dead,
+}
+
+// the result of sending a delivery once
+pub struct Attempt
+{
+    pub outcome: Outcome,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebhookRequest
+{
+    url: String,
+    events: Vec<String>,
+    secret: String,
+}
+
+// subscribe a URL to events, answering with the webhook (without its secret)
+pub async fn handle_create_request(req: &Request, webhooks: &dyn WebhookRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    principal.active()?.can_manage_webhooks()?;
+
+    let request: WebhookRequest = parse_json(req)?;
+
+    let mut errors = Vec::new();
+
+    if !request.url.parse::<Url>().is_ok_and(|url| url.scheme == "http")
+    {
+        errors.push(FieldError::new("url", "invalid_url", "Must be an http:// URL, https is not supported."));
+    }
+
+    let mut events = request.events;
+
+    events.sort();
+    events.dedup();
+
+    if events.is_empty()
+    {
+        errors.push(FieldError::new("events", "required", "Must name at least one event."));
+    }
+
+    if events.iter().any(|event| Kind::from_topic(event).is_none())
+    {
+        errors.push(FieldError::new("events", "unknown_event", "Must be created, updated, banned, unbanned or deleted."));
+    }
+
+    match request.secret.chars().count()
+    {
+        length if length < MIN_SECRET_LENGTH => errors.push(FieldError::new("secret", "too_short", &format!("Must be at least {} characters long.", MIN_SECRET_LENGTH))),
+        length if length > MAX_SECRET_LENGTH => errors.push(FieldError::new("secret", "too_long", &format!("Must be at most {} characters long.", MAX_SECRET_LENGTH))),
+        _ => {}
+    }
+
+    if !errors.is_empty()
+    {
+        return Err(ApiError::Validation(errors));
+    }
+
+    let webhook = webhooks.create_webhook(NewWebhook { url: request.url, events, secret: request.secret }).await?;
+
+    Ok(Response::json(201, serde_json::to_string(&webhook).unwrap()))
+}
+
+// every webhook, oldest first
+pub async fn handle_list_request(webhooks: &dyn WebhookRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    principal.active()?.can_manage_webhooks()?;
+
+    let all = webhooks.webhooks().await?;
+
+    Ok(Response::json(200, serde_json::json!({ "data": all }).to_string()))
+}
+
+pub async fn handle_get_request(params: &Params, webhooks: &dyn WebhookRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_manage_webhooks()?;
+
+    let webhook = webhooks.webhook(id).await?.ok_or(ApiError::WebhookNotFound)?;
+
+    Ok(Response::json(200, serde_json::to_string(&webhook).unwrap()))
+}
+
+// unsubscribe, the deliveries still waiting are dropped along with the log
+pub async fn handle_delete_request(params: &Params, webhooks: &dyn WebhookRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_manage_webhooks()?;
+
+    match webhooks.delete_webhook(id).await?
+    {
+        true => Ok(Response::new(204)),
+        false => Err(ApiError::WebhookNotFound),
+    }
+}
+
+// the delivery log of a webhook, newest first
+pub async fn handle_deliveries_request(req: &Request, params: &Params, webhooks: &dyn WebhookRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_manage_webhooks()?;
+
+    let query = Query::parse(req.query.as_deref()).ok_or(ApiError::MalformedRequest("invalid percent-encoding in query string"))?;
+    let query = DeliveryQuery::from_query(&query, &req.path)?;
+
+    let page = webhooks.deliveries(id, &query).await?.ok_or(ApiError::WebhookNotFound)?;
+
+    Ok(Response::json(200, serde_json::json!({ "data": page.deliveries, "next": page.next }).to_string()))
+}
+
+// send a ping right away and answer with how it went, a failed ping is not retried
+pub async fn handle_test_request(params: &Params, webhooks: &dyn WebhookRepository, principal: &Principal) -> Result<Response, ApiError>
+{
+    let id: Uuid = params.get("id").ok_or(ApiError::InvalidId)?;
+
+    principal.active()?.can_manage_webhooks()?;
+
+    let payload = serde_json::json!({ "type": "Ping", "webhook_id": id, "occurred_at": timestamp::format(SystemTime::now()) });
+
+    let (webhook, delivery) = webhooks.create_ping(id, payload, LEASE).await?.ok_or(ApiError::WebhookNotFound)?;
+
+    let attempt = attempt(&webhook, &delivery, false).await;
+    let delivery = webhooks.record_attempt(delivery.id, &attempt).await?.ok_or(ApiError::WebhookNotFound)?;
+
+    Ok(Response::json(200, serde_json::to_string(&delivery).unwrap()))
+}
+
+// periodically send the deliveries that are due, every replica runs this and each claims its own
+pub fn spawn_delivery_task(webhooks: Arc<dyn WebhookRepository>, interval: Duration)
+{
+    tokio::spawn(async move
+    {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop
+        {
+            ticker.tick().await;
+
+            let due = match webhooks.claim_deliveries(BATCH_SIZE, LEASE).await
+            {
+                Ok(due) => due,
+                Err(e) =>
+                {
+                    log::warn!("Cannot claim webhook deliveries: {:?}", e);
+                    continue;
+                }
+            };
+
+            // one slow webhook must not hold up the others
+            let mut sending = JoinSet::new();
+
+            for (webhook, delivery) in due
+            {
+                let webhooks = webhooks.clone();
+
+                sending.spawn(async move
+                {
+                    let attempt = attempt(&webhook, &delivery, true).await;
+
+                    if let Err(e) = webhooks.record_attempt(delivery.id, &attempt).await
+                    {
+                        log::warn!("Cannot record the attempt of delivery {}: {:?}", delivery.id, e);
+                    }
+                });
+            }
+
+            while sending.join_next().await.is_some() {}
+        }
+    });
+}
+
+// send a delivery once, a failure is retried later when retry is set and attempts are left
+async fn attempt(webhook: &Webhook, delivery: &Delivery, retry: bool) -> Attempt
+{
+    let result = match tokio::time::timeout(DELIVERY_TIMEOUT, send(webhook, delivery)).await
+    {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "the webhook did not answer in time")),
+    };
+
+    let (response_status, error) = match result
+    {
+        Ok(status @ 200..=299) => return Attempt { outcome: Outcome::Delivered, response_status: Some(status as i32), error: None },
+        Ok(status) => (Some(status as i32), format!("the webhook answered {}", status)),
+        Err(e) => (None, e.to_string()),
+    };
+
+    let attempts = delivery.attempts + 1;
+
+    let outcome = match retry && attempts < MAX_ATTEMPTS
+    {
+        true => Outcome::Retry(SystemTime::now() + backoff(attempts)),
+        false => Outcome::Dead,
+    };
+
+    Attempt { outcome, response_status, error: Some(error) }
+}
+
+// POST the payload, signed with the secret of the webhook
+async fn send(webhook: &Webhook, delivery: &Delivery) -> std::io::Result<u16>
+{
+    let url: Url = webhook.url.parse().map_err(std::io::Error::other)?;
+
+    let body = delivery.payload.to_string();
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs().to_string();
+
+    let headers =
+    [
+        ("Content-Type", "application/json".to_string()),
+        ("X-Webhook-Id", webhook.id.to_string()),
+        ("X-Webhook-Delivery", delivery.id.to_string()),
+        ("X-Webhook-Event", delivery.event.clone()),
+        ("X-Webhook-Timestamp", time.clone()),
+        ("X-Webhook-Signature", format!("sha256={}", signature(&webhook.secret, &time, &body))),
+    ];
+
+    http::post(&url, &headers, body.as_bytes()).await
+}
+
+// hex HMAC-SHA256 of "{timestamp}.{body}", the timestamp is signed as well so a captured request cannot be replayed later
+pub fn signature(secret: &str, time: &str, body: &str) -> String
+{
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+
+    mac.update(time.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// the delay before the next attempt after the given number of failed ones
+fn backoff(attempts: i32) -> Duration
+{
+    FIRST_RETRY.saturating_mul(1 << (attempts - 1).clamp(0, 16)).min(MAX_RETRY_DELAY)
+}